@@ -6,6 +6,9 @@ pub use buddy::{order_for_size, size_for_order, BuddyAllocator};
 pub mod rangeset;
 pub use rangeset::RangeSet;
 
+pub mod slab;
+pub use slab::SlabAllocator;
+
 use crate::unit;
 use core::fmt;
 
@@ -36,7 +39,7 @@ pub enum Error {
     NoMemoryAvailable,
     /// can't allocate zero pages
     AllocateZeroPages,
-    /// this is not a real error and should never be thrown somewhere
+    /// the layout is too large to be served by a slab allocator
     NoSlabForLayout,
     /// `NonNull` was null
     ///
@@ -4,8 +4,9 @@ use core::{cmp, ptr::NonNull};
 
 /// The maximum order for the buddy allocator (inclusive).
 ///
-/// This means, that orders 0..MAX_ORDER are available.
-pub const MAX_ORDER: usize = 12;
+/// This means, that orders 0..MAX_ORDER are available, which is enough
+/// to allocate a single page of every page size.
+pub const MAX_ORDER: usize = 28;
 
 /// The size of the order `0`, thus it's the smallest possible size
 /// that can be allocated.
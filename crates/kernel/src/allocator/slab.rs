@@ -0,0 +1,124 @@
+//! A slab allocator that serves small allocations out of fixed size classes.
+
+use super::{AllocStats, Error, Result, PAGE_SIZE};
+use core::{alloc::Layout, cmp, ptr::NonNull};
+
+/// The object size for each slab.
+///
+/// Layouts that don't fit into the largest size class can't be allocated
+/// by a slab allocator.
+pub const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Return the index of the smallest size class that can hold the given layout.
+pub fn class_for_layout(layout: Layout) -> Option<usize> {
+    // every size class is a power of two and each slab is filled using page aligned memory,
+    // so every object is aligned to its own size
+    let size = cmp::max(layout.size(), layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// A single slab, which is a free list of objects with the same size.
+#[derive(Clone, Copy)]
+struct Slab {
+    free: Option<NonNull<FreeObject>>,
+}
+
+impl Slab {
+    fn push(&mut self, obj: NonNull<u8>) {
+        let obj = obj.cast::<FreeObject>();
+        unsafe { obj.as_ptr().write(FreeObject { next: self.free }) };
+        self.free = Some(obj);
+    }
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        let head = self.free?;
+        self.free = unsafe { head.as_ref().next };
+        Some(head.cast())
+    }
+}
+
+/// The central structure that manages a slab for every [size class](SIZE_CLASSES).
+///
+/// The slab allocator doesn't allocate the backing memory itself. If a slab is empty,
+/// an allocation will fail with [`Error::NoMemoryAvailable`], and the slab has to be
+/// refilled using [`SlabAllocator::grow`].
+pub struct SlabAllocator {
+    slabs: [Slab; SIZE_CLASSES.len()],
+    stats: AllocStats,
+}
+
+unsafe impl Send for SlabAllocator {}
+
+impl SlabAllocator {
+    /// Create a new slab allocator where every slab is empty.
+    pub const fn new() -> Self {
+        Self {
+            slabs: [Slab { free: None }; SIZE_CLASSES.len()],
+            stats: AllocStats::with_name("Kernel Heap"),
+        }
+    }
+
+    /// Allocate an object that can hold the given layout.
+    ///
+    /// Returns [`Error::NoSlabForLayout`] if the layout is too large for every slab,
+    /// and [`Error::NoMemoryAvailable`] if the slab for the layout must grow first.
+    pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>> {
+        let class = class_for_layout(layout).ok_or(Error::NoSlabForLayout)?;
+        let obj = self.slabs[class].pop().ok_or(Error::NoMemoryAvailable)?;
+
+        // update statistics
+        self.stats.free -= SIZE_CLASSES[class];
+        self.stats.allocated += SIZE_CLASSES[class];
+
+        Ok(obj)
+    }
+
+    /// Deallocate an object that was allocated with the given layout.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be allocated by `self` using the [`Self::allocate`] method,
+    /// with the same layout as given here.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) -> Result<()> {
+        let class = class_for_layout(layout).ok_or(Error::NoSlabForLayout)?;
+        self.slabs[class].push(ptr);
+
+        // update statistics
+        self.stats.free += SIZE_CLASSES[class];
+        self.stats.allocated -= SIZE_CLASSES[class];
+
+        Ok(())
+    }
+
+    /// Split the given page into objects and add them to the slab that serves `layout`.
+    ///
+    /// # Safety
+    ///
+    /// `page` must be a page aligned, writable pointer to [`PAGE_SIZE`] bytes that are
+    /// not used by anything else.
+    pub unsafe fn grow(&mut self, layout: Layout, page: NonNull<u8>) -> Result<()> {
+        let class = class_for_layout(layout).ok_or(Error::NoSlabForLayout)?;
+        let size = SIZE_CLASSES[class];
+
+        // push the objects in reverse, so they are handed out in ascending order
+        for off in (0..PAGE_SIZE).step_by(size).rev() {
+            let obj = NonNull::new_unchecked(page.as_ptr().add(off));
+            self.slabs[class].push(obj);
+        }
+
+        // update statistics
+        self.stats.total += PAGE_SIZE;
+        self.stats.free += PAGE_SIZE;
+
+        Ok(())
+    }
+
+    /// Return a copy of the statistics of this slab allocator.
+    pub fn stats(&self) -> AllocStats {
+        self.stats.clone()
+    }
+}
@@ -0,0 +1,103 @@
+//! Frame pointer based stack unwinding.
+//!
+//! The kernel is compiled with frame pointers, so every function stores its return address
+//! at `fp - 8` and the frame pointer of its caller at `fp - 16`, where `fp` is the value
+//! of the `s0` register. The prebuilt `core` and `alloc` crates don't maintain frame pointers,
+//! so frames of these crates may be missing, or end the backtrace early.
+//!
+//! All addresses are reported as they are inside the kernel ELF file, which means the
+//! offset of [`relocate`](crate::boot::reloc::relocate) is removed again, so they can be
+//! passed to `addr2line` directly. If the kernel contains a symbol table, the function
+//! of every address is printed too.
+
+use crate::{
+    memmap::KERNEL_STACK_SIZE,
+    symbols::{self, LINK_START},
+};
+use core::fmt;
+
+/// The maximum number of frames that are walked.
+pub const MAX_FRAMES: usize = 64;
+
+/// An iterator over the return addresses of every frame on the stack.
+#[derive(Debug, Clone)]
+pub struct Backtrace {
+    fp: usize,
+    depth: usize,
+}
+
+impl Backtrace {
+    /// Start walking at the frame of the function that called this function.
+    #[inline(always)]
+    pub fn here() -> Self {
+        let fp: usize;
+        unsafe { asm!("mv {}, s0", out(reg) fp) };
+        Self::from_fp(fp)
+    }
+
+    /// Start walking at the frame that is described by the given frame pointer.
+    pub fn from_fp(fp: usize) -> Self {
+        Self { fp, depth: 0 }
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.fp == 0 || self.fp % 8 != 0 || self.depth >= MAX_FRAMES {
+            return None;
+        }
+
+        let (ra, prev_fp) = unsafe {
+            let fp = self.fp as *const usize;
+            (*fp.sub(1), *fp.sub(2))
+        };
+
+        // a return address outside of the kernel means the frame pointer was garbage
+        if !is_kernel_text(ra) {
+            return None;
+        }
+
+        // the stack grows downwards, so the frame of the caller must be above this frame,
+        // but it can't be further away than the size of a stack
+        self.fp = match prev_fp.checked_sub(self.fp) {
+            Some(dist) if dist > 0 && dist <= KERNEL_STACK_SIZE => prev_fp,
+            _ => 0,
+        };
+        self.depth += 1;
+
+        Some(ra)
+    }
+}
+
+/// Display helper that prints every frame of a backtrace on its own line.
+pub struct Display(pub Backtrace);
+
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, ra) in self.0.clone().enumerate() {
+            writeln!(
+                f,
+                "  {:>2}: {:#x} {}",
+                idx,
+                unrelocate(ra),
+                symbols::Display(ra)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Check if the given address is inside the `.text` section of the kernel.
+pub fn is_kernel_text(addr: usize) -> bool {
+    let (start, end) = symbols::text_range();
+    (start as usize..end as usize).contains(&addr)
+}
+
+/// Convert an address inside the running kernel into the address
+/// it has inside the kernel ELF file.
+pub fn unrelocate(addr: usize) -> usize {
+    let (start, _) = symbols::kernel_range();
+    addr.wrapping_sub(start as usize).wrapping_add(LINK_START)
+}
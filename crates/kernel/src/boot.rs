@@ -5,18 +5,18 @@ mod reloc;
 
 use crate::{
     allocator::{self, order_for_size, size_for_order, PAGE_SIZE},
-    hart,
-    memmap::{self, KERNEL_PHYS_MEM_BASE, KERNEL_STACK_BASE, KERNEL_STACK_SIZE},
-    page::{Flags, KernelPageTable, PageSize, PhysAddr, VirtAddr},
-    pmem, symbols, trap, unit,
+    drivers, hart,
+    memmap::{self, Layout, KERNEL_STACK_SIZE},
+    page::{self, Flags, KernelPageTable, PhysAddr, VirtAddr},
+    pmem, symbols, thread, time, tlb, trap,
 };
 use alloc::boxed::Box;
 use core::slice;
 use devicetree::DeviceTree;
-use riscv::sync::Mutex;
+use riscv::sync::IrqMutex;
 
 #[doc(hidden)]
-pub(crate) static PAGE_TABLE: Mutex<Option<KernelPageTable>> = Mutex::new(None);
+pub(crate) static PAGE_TABLE: IrqMutex<Option<KernelPageTable>> = IrqMutex::new(None);
 
 struct SbiLogger;
 impl log::Logger for SbiLogger {
@@ -30,26 +30,26 @@ impl log::Logger for SbiLogger {
 /// of the new stack.
 ///
 /// Returns both, the physical and virtual address to the end of the stack.
+/// The page below the stack stays unmapped, so an overflow can be detected.
 pub(self) fn alloc_kernel_stack(table: &mut KernelPageTable, id: u64) -> (PhysAddr, VirtAddr) {
     // calculate the start address for hart `id`s stack
-    let start = KERNEL_STACK_BASE + id as usize * KERNEL_STACK_SIZE;
+    let base = memmap::layout().kernel_stack_base;
+    let start = memmap::stack_start(base, KERNEL_STACK_SIZE, id as usize);
 
     // allocate the backing physmem
     let stack = pmem::alloc_order(allocator::order_for_size(KERNEL_STACK_SIZE))
         .unwrap()
         .as_ptr();
 
-    // allocate the new stack
-    for off in (0..KERNEL_STACK_SIZE).step_by(PAGE_SIZE) {
-        table
-            .map(
-                unsafe { stack.add(off) }.into(),
-                (start + off).into(),
-                PageSize::Kilopage,
-                Flags::READ | Flags::WRITE | Flags::ACCESSED | Flags::DIRTY,
-            )
-            .unwrap();
-    }
+    // map the new stack
+    table
+        .map_range(
+            stack.into(),
+            start.into(),
+            KERNEL_STACK_SIZE,
+            Flags::READ | Flags::WRITE | Flags::ACCESSED | Flags::DIRTY,
+        )
+        .unwrap();
 
     (
         PhysAddr::from(stack as usize + KERNEL_STACK_SIZE),
@@ -72,6 +72,12 @@ unsafe extern "C" fn _before_main(hart_id: usize, fdt: *const u8) -> ! {
     // initialize the physmem allocator
     pmem::init(&fdt).unwrap();
 
+    // choose the paging mode, and the memory layout that fits into it
+    let mode = page::modes::probe(&fdt).expect("no supported paging mode");
+    page::modes::select(mode.clone());
+    memmap::set_layout(Layout::for_mode(&mode));
+    let layout = memmap::layout();
+
     // get access to the global page table
     let mut table_lock = PAGE_TABLE.lock();
     let table = table_lock.get_or_insert_with(|| KernelPageTable::new());
@@ -88,19 +94,16 @@ unsafe extern "C" fn _before_main(hart_id: usize, fdt: *const u8) -> ! {
     let fdt: DeviceTree<'static> = fdt.copy_to_slice(new_fdt);
 
     // get all available physical memory from the devicetree and map it
-    // at the physmem base
+    // at the physmem base, using the largest possible pages
     let phys_mem = fdt.memory().regions().next().unwrap();
-    for page in (phys_mem.start()..phys_mem.end()).step_by(2 * unit::MIB) {
-        let vaddr = page + KERNEL_PHYS_MEM_BASE;
-        table
-            .map(
-                page.into(),
-                vaddr.into(),
-                PageSize::Megapage,
-                Flags::READ | Flags::WRITE | Flags::ACCESSED | Flags::DIRTY,
-            )
-            .unwrap();
-    }
+    table
+        .map_range(
+            phys_mem.start().into(),
+            (phys_mem.start() + layout.phys_mem_base).into(),
+            allocator::align_up(phys_mem.size(), PAGE_SIZE),
+            Flags::READ | Flags::WRITE | Flags::ACCESSED | Flags::DIRTY,
+        )
+        .unwrap();
 
     // get the base address of the real memory location where the kernel currently is
     let (base, _) = symbols::kernel_range();
@@ -110,21 +113,17 @@ unsafe extern "C" fn _before_main(hart_id: usize, fdt: *const u8) -> ! {
     let mut map_section = |(o_start, o_end): (*mut u8, *mut u8), perm: Flags| {
         // only get the offset of this section
         let (start, end) = (o_start as usize - base, o_end as usize - base);
+        let size = allocator::align_up(end - start, PAGE_SIZE);
 
-        for section_off in (start as usize..end as usize).step_by(PAGE_SIZE) {
-            // map the physical page to the same offset in the higher half of address space
-            let phys = base + section_off;
-            let virt = memmap::HIGHER_HALF_START + section_off;
-
-            table
-                .map(
-                    phys.into(),
-                    virt.into(),
-                    PageSize::Kilopage,
-                    perm | Flags::ACCESSED | Flags::DIRTY,
-                )
-                .unwrap();
-        }
+        // map the physical pages to the same offset in the higher half of address space
+        table
+            .map_range(
+                (base + start).into(),
+                (layout.higher_half_start + start).into(),
+                size,
+                perm | Flags::ACCESSED | Flags::DIRTY,
+            )
+            .unwrap();
     };
 
     map_section(symbols::text_range(), Flags::READ | Flags::EXEC);
@@ -137,10 +136,16 @@ unsafe extern "C" fn _before_main(hart_id: usize, fdt: *const u8) -> ! {
     // allocate the stack for this hart
     let (phys_stack, virt_stack) = alloc_kernel_stack(table, hart_id as u64);
 
+    // every process shares the top level entries of the kernel half, so they have to
+    // exist before the first process is created
+    table
+        .alloc_top_level(layout.user_space_end().into())
+        .unwrap();
+
     // calculate the address for the function to trampoline into
     let real_addr = rust_trampoline as usize;
     let off = real_addr - base;
-    let virt_addr = memmap::HIGHER_HALF_START + off;
+    let virt_addr = layout.higher_half_start + off;
 
     let satp = table.satp();
 
@@ -153,8 +158,8 @@ unsafe extern "C" fn _before_main(hart_id: usize, fdt: *const u8) -> ! {
             let (entries, (ptr, len, cap)) = me.into_raw_parts();
 
             // convert the pointers to virtual addresses
-            let entries = entries.cast::<u8>().add(KERNEL_PHYS_MEM_BASE).cast();
-            let ptr = ptr.cast::<u8>().add(KERNEL_PHYS_MEM_BASE).cast();
+            let entries = entries.cast::<u8>().add(layout.phys_mem_base).cast();
+            let ptr = ptr.cast::<u8>().add(layout.phys_mem_base).cast();
 
             // create the new, converted page table
             let table = KernelPageTable::from_raw_parts(
@@ -169,15 +174,15 @@ unsafe extern "C" fn _before_main(hart_id: usize, fdt: *const u8) -> ! {
     drop(table_lock);
 
     // set the physical memory offset
-    memmap::set_phymem_offset(KERNEL_PHYS_MEM_BASE);
+    memmap::set_phymem_offset(layout.phys_mem_base);
 
     // relocate kernel before jumping to virtual memory
-    assert_eq!(reloc::relocate(memmap::HIGHER_HALF_START), 0);
+    assert_eq!(reloc::relocate(layout.higher_half_start), 0);
 
     // jump to rust code using the trampoline
     entry_trampoline(
         hart_id,
-        fdt.as_ptr().add(KERNEL_PHYS_MEM_BASE),
+        fdt.as_ptr().add(layout.phys_mem_base),
         satp.as_bits(),
         virt_stack.into(),
         virt_addr,
@@ -218,6 +223,24 @@ unsafe extern "C" fn rust_trampoline(hart_id: usize, fdt: *const u8, satp: u64)
     let fdt = DeviceTree::from_ptr(fdt).unwrap();
     hart::init_hart_context(hart_id as u64, hart_id as u64, fdt).unwrap();
 
+    // the lock validator needs to know which hart holds a lock
+    #[cfg(debug_assertions)]
+    riscv::sync::lockdep::set_hart_id(|| hart::try_current().map(|ctx| ctx.id() as usize));
+
+    // read the timebase frequency before anything depends on the time
+    time::init(&fdt);
+
+    // remember the kernel address space, and find out how many ASIDs are available
+    page::set_kernel_satp(satp as usize);
+    page::asid::init();
+
+    // find out if the TLBs of other harts can be flushed
+    tlb::init();
+
+    // start collecting statistics of the global locks
+    #[cfg(feature = "lock-stats")]
+    crate::lockstat::init();
+
     // install the interrupt handler
     trap::install_handler();
 
@@ -227,9 +250,23 @@ unsafe extern "C" fn rust_trampoline(hart_id: usize, fdt: *const u8, satp: u64)
     // initialize hart local storage and hart context
     hart::init_hart_local_storage().unwrap();
 
-    // FIXME: Currently broken
-    // boot up the other harts
-    // harts::boot_all_harts(hart_id, fdt, satp);
+    // instantiate all device drivers and route their interrupts to this hart
+    drivers::manager::init(&fdt);
+    let devices = drivers::manager::manager().unwrap();
+    devices.enable_interrupts(hart::current().plic_context());
+
+    // switch over to the stdout device as our logger, if there's a driver for it.
+    // otherwise we keep using the SBI logger.
+    if let Some(logger) = devices.stdout().and_then(|dev| dev.as_logger()) {
+        log::init_log(logger).map_err(|_| ()).unwrap();
+    }
+
+    // turn this code into the first thread of this hart
+    thread::init();
+
+    // boot up the other harts and wait until they are online
+    let started = harts::boot_all_harts(hart_id, fdt, satp);
+    harts::wait_for_harts(started);
 
     // jump into safe rust code
     crate::main(&fdt)
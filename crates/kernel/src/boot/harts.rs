@@ -1,18 +1,29 @@
 //! Code to bringup all secondary harts.
 
-use crate::{hart, page, trap};
+use crate::{hart, page, thread, trap};
+use core::time::Duration;
 use devicetree::DeviceTree;
 
+/// The maximum time the boot hart waits for all other harts to come online.
+const HART_BOOT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The arguments that are passed to a hart that is started.
+///
+/// The first three fields are read by the assembly in `hart_entry`,
+/// so keep their order in sync.
 #[repr(C)]
 struct HartArgs {
     satp: u64,
     vstack: u64,
+    entry: u64,
     boot_hart: u64,
     fdt: DeviceTree<'static>,
 }
 
 /// Boot all harts that are present in the given devicetree.
-pub(super) unsafe fn boot_all_harts(hart_id: usize, fdt: DeviceTree<'_>, satp: u64) {
+///
+/// Returns a bitmask of all harts that were started successfully.
+pub(super) unsafe fn boot_all_harts(hart_id: usize, fdt: DeviceTree<'_>, satp: u64) -> u64 {
     // extract all harts that do not have our id from the devicetree
     let cores = fdt
         .find_nodes("/cpus/cpu@")
@@ -21,8 +32,26 @@ pub(super) unsafe fn boot_all_harts(hart_id: usize, fdt: DeviceTree<'_>, satp: u
 
     let mut table = page::root();
 
+    // the entrypoint is called while paging is still disabled,
+    // so we have to give SBI the physical address of it
+    let (entry, _, _) = table
+        .translate((hart_entry as usize).into())
+        .expect("hart entrypoint is not mapped");
+
     // go through each hart and try to boot it
+    let mut started = 0;
     for hart in cores {
+        // every hart needs a bit inside the `started` mask and a slot for its context
+        if hart as usize >= hart::MAX_HARTS {
+            log::warn!(
+                "{} hart {}, only ids below {} are supported",
+                "Skipping".yellow(),
+                hart,
+                hart::MAX_HARTS
+            );
+            continue;
+        }
+
         // allocate stack in physical memory, since it will be mapped in later by the hart
         let (pstack, vstack) = super::alloc_kernel_stack(&mut *table, hart as u64);
 
@@ -35,20 +64,54 @@ pub(super) unsafe fn boot_all_harts(hart_id: usize, fdt: DeviceTree<'_>, satp: u
             boot_hart: hart_id as u64,
             satp,
             vstack: vstack as u64,
+            entry: rust_hart_entry as usize as u64,
         };
 
         vstack.write(args);
 
         // try to start the hart
-        match sbi::hsm::start(hart as usize, hart_entry as usize, pstack as usize) {
-            Ok(()) => {}
+        match sbi::hsm::start(hart as usize, entry.into(), pstack as usize) {
+            Ok(()) => started |= 1 << hart,
             Err(err) => log::warn!("{} to boot hart {}: {:?}", "Failed".yellow(), hart, err),
         }
     }
+
+    started
+}
+
+/// Wait until every hart inside the `started` mask reported itself online.
+///
+/// If a hart doesn't come up before the timeout, a warning containing its
+/// status is printed.
+pub(super) fn wait_for_harts(started: u64) {
+    let deadline = riscv::asm::time() + HART_BOOT_TIMEOUT;
+
+    // returns an iterator over all harts that were started but are not online yet
+    let pending =
+        || (0..u64::BITS as u64).filter(|id| started & (1 << id) != 0 && !hart::is_online(*id));
+
+    // spin until every hart is online or we hit the deadline
+    while pending().next().is_some() && riscv::asm::time() < deadline {
+        core::hint::spin_loop();
+    }
+
+    // report every hart that failed to come online
+    for id in pending() {
+        log::warn!(
+            "{} to bring up hart {} (status: {:?})",
+            "Failed".yellow(),
+            id,
+            sbi::hsm::status(id as usize),
+        );
+    }
 }
 
+/// The physical entrypoint for every secondary hart.
+///
+/// `a0` = hart id
+/// `a1` = physical pointer to the `HartArgs`
 #[naked]
-unsafe extern "C" fn hart_entry(_hart_id: usize, _sp: usize) -> ! {
+unsafe extern "C" fn hart_entry(_hart_id: usize, _args: usize) -> ! {
     asm!(
         "
         # Load the global pointer into
@@ -58,39 +121,53 @@ unsafe extern "C" fn hart_entry(_hart_id: usize, _sp: usize) -> ! {
             lla gp, __global_pointer$
         .option pop
 
+        # Disable interrupts and clear the hart context
+        csrw sie, zero
+        csrci sstatus, 2
+        csrw sscratch, zero
+
         # Load arguments from stack
         #   t0: satp
         #   t1: virtual stack
+        #   t2: virtual address of the rust entrypoint
         ld t0, 0(a1)
         ld t1, 8(a1)
+        ld t2, 16(a1)
 
         # Load the virtual stack and arguments
         mv sp, t1
         mv a1, t1
 
-        # Jump into rust code by enabling paging and trapping to the function
-        lla t1, {}
-        csrw stvec, t1
+        # Prepare the stvec register, so after enabling paging, we trap into the rust code
+        csrw stvec, t2
 
+        # Enable paging. This will trap because this code here is not mapped anymore.
+        csrw satp, t0
         sfence.vma
         nop
     ",
-        sym rust_hart_entry,
         options(noreturn)
     )
 }
 
+/// The virtual entrypoint for every secondary hart, which is running on the new stack.
 #[repr(align(4))]
 unsafe extern "C" fn rust_hart_entry(hart_id: u64, args: &HartArgs) -> ! {
-    log::debug!("HI");
-    loop {}
     // initialize hart local storage and hart context
-    //hart::init_hart_local_storage().unwrap();
-    //hart::init_hart_context(hart_id, args.boot_hart, args.fdt).unwrap();
+    hart::init_hart_context(hart_id, args.boot_hart, args.fdt).unwrap();
+    hart::init_hart_local_storage().unwrap();
+
+    // route device interrupts to this hart
+    if let Some(dev) = hart::current().devices() {
+        dev.enable_interrupts(hart::current().plic_context());
+    }
+
+    // install trap handler
+    trap::install_handler();
 
-    //// install trap handler
-    //trap::install_handler();
+    // turn this code into the first thread of this hart
+    thread::init();
 
-    //// after setting up everything, we're ready to jump into safe rust code
+    // after setting up everything, we're ready to jump into safe rust code
     crate::hmain()
 }
@@ -1,17 +1,9 @@
 //! Implementation of dynamic relocation of the kernel itself.
 
-use crate::symbols::{kernel_range, LINK_START};
-
-mod elf {
-    pub const R_RISCV_RELATIVE: usize = 3;
-
-    #[repr(C)]
-    pub struct Rela {
-        pub offset: usize,
-        pub info: usize,
-        pub addend: isize,
-    }
-}
+use crate::{
+    elf,
+    symbols::{kernel_range, LINK_START},
+};
 
 /// Relocate the kernel to the given base address.
 pub unsafe extern "C" fn relocate(base: usize) -> u32 {
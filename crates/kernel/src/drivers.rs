@@ -1,3 +1,6 @@
+pub mod manager;
+pub use manager::DeviceManager;
+
 pub mod ns16550a;
 pub mod plic;
 
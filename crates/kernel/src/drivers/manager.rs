@@ -0,0 +1,195 @@
+//! The device manager, which is responsible for instantiating all drivers
+//! and routing external interrupts to them.
+
+use super::{ns16550a, plic, DeviceDriver};
+use crate::{
+    allocator::{align_up, PAGE_SIZE},
+    memmap::phys2virt,
+    page::{self, Flags, PhysAddr},
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use devicetree::{node::Node, DeviceTree};
+
+/// The global device manager, which is initialized once by the boot hart.
+static DEVICE_MANAGER: AtomicPtr<DeviceManager> = AtomicPtr::new(ptr::null_mut());
+
+/// Function that tries to create a driver instance for a node.
+type Probe = fn(&Node<'_>) -> Option<Box<dyn DeviceDriver>>;
+
+/// All drivers that can be instantiated by the device manager.
+const DRIVERS: &[Probe] = &[probe::<ns16550a::Device>];
+
+/// The threshold each hart's PLIC context is configured with.
+const PLIC_THRESHOLD: u32 = 0;
+
+fn probe<D: DeviceDriver + 'static>(node: &Node<'_>) -> Option<Box<dyn DeviceDriver>> {
+    if !D::compatible_with(node) {
+        return None;
+    }
+
+    map_regions(node)?;
+    let driver = D::from_node(node)?;
+    Some(Box::new(driver))
+}
+
+/// Map all regions of the given node into the physical memory window,
+/// so the driver can access it using [`phys2virt`].
+///
+/// Returns `None` and logs a warning, if a region couldn't be mapped.
+fn map_regions(node: &Node<'_>) -> Option<()> {
+    let mut table = page::root();
+
+    for region in node.regions() {
+        let start = region.start() & !(PAGE_SIZE - 1);
+        let end = align_up(region.end(), PAGE_SIZE);
+
+        let mut addr = start;
+        while addr < end {
+            // multiple devices may share the same page, so skip the pages that are already mapped
+            let vaddr = usize::from(phys2virt(addr));
+            if let Some((_, size, _)) = table.translate(vaddr.into()) {
+                addr += size.size() - (vaddr & (size.size() - 1));
+                continue;
+            }
+
+            // map everything up to the next mapped page at once, so huge pages can be used
+            let run_end = (addr..end)
+                .step_by(PAGE_SIZE)
+                .find(|&page| table.translate(phys2virt(page)).is_some())
+                .unwrap_or(end);
+
+            let res = table.map_range(
+                PhysAddr::from(addr),
+                phys2virt(addr),
+                run_end - addr,
+                Flags::READ | Flags::WRITE | Flags::ACCESSED | Flags::DIRTY,
+            );
+
+            if let Err(err) = res {
+                log::warn!(
+                    "{} to map the registers of `{}`: {:?}",
+                    "Failed".yellow(),
+                    node.name(),
+                    err
+                );
+                return None;
+            }
+
+            addr = run_end;
+        }
+    }
+
+    Some(())
+}
+
+/// Structure that holds every driver that was found inside the devicetree.
+pub struct DeviceManager {
+    plic: Option<plic::Controller>,
+    devices: Vec<Box<dyn DeviceDriver>>,
+    /// The index of the driver for the `/chosen/stdout-path` node.
+    stdout: Option<usize>,
+}
+
+unsafe impl Send for DeviceManager {}
+unsafe impl Sync for DeviceManager {}
+
+impl DeviceManager {
+    /// Walk the devicetree, and instantiate and initialize every node that
+    /// is compatible with one of our drivers.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called once, because every driver will be initialized.
+    pub unsafe fn from_devicetree(fdt: &DeviceTree<'_>) -> Self {
+        let mut plic = None;
+        let mut devices = Vec::new();
+        let mut stdout = None;
+
+        // the name of the node that should be used as the standard output
+        let stdout_name = fdt.chosen().stdout().map(|node| node.name());
+
+        for node in fdt.nodes() {
+            if plic.is_none() && plic::Controller::compatible_with(&node) {
+                plic = map_regions(&node).and_then(|_| plic::Controller::from_node(&node));
+                continue;
+            }
+
+            if let Some(dev) = DRIVERS.iter().find_map(|probe| probe(&node)) {
+                if stdout_name == Some(node.name()) {
+                    stdout = Some(devices.len());
+                }
+
+                devices.push(dev);
+            }
+        }
+
+        // initialize the interrupt controller first, so devices can use interrupts
+        if let Some(plic) = &plic {
+            plic.init();
+        }
+        devices.iter().for_each(|dev| dev.init());
+
+        Self {
+            plic,
+            devices,
+            stdout,
+        }
+    }
+
+    /// Return the interrupt controller, if there is one.
+    pub fn plic(&self) -> Option<&plic::Controller> {
+        self.plic.as_ref()
+    }
+
+    /// Return an iterator over all drivers that are managed by this manager.
+    pub fn devices(&self) -> impl Iterator<Item = &dyn DeviceDriver> {
+        self.devices.iter().map(|dev| &**dev)
+    }
+
+    /// Return the driver for the node that is specified as the standard output
+    /// inside the `/chosen` node.
+    pub fn stdout(&self) -> Option<&dyn DeviceDriver> {
+        self.stdout.map(|idx| &*self.devices[idx])
+    }
+
+    /// Enable the interrupts of every device on the given PLIC context.
+    pub fn enable_interrupts(&self, ctx: plic::Context) {
+        let plic = match &self.plic {
+            Some(plic) => plic,
+            None => return,
+        };
+
+        self.devices()
+            .filter_map(|dev| dev.as_interruptable())
+            .for_each(|dev| plic.enable(ctx, dev.interrupt_id()));
+        plic.set_threshold(ctx, PLIC_THRESHOLD);
+    }
+
+    /// Route the interrupt with the given id to the driver that is responsible for it.
+    pub fn handle_interrupt(&self, id: u32) -> Result<(), &'static str> {
+        let dev = self
+            .devices()
+            .filter_map(|dev| dev.as_interruptable())
+            .find(|dev| dev.interrupt_id() == id)
+            .ok_or("no driver for interrupt")?;
+
+        dev.handle_interrupt(id)
+    }
+}
+
+/// Create the global device manager from the given devicetree.
+///
+/// # Safety
+///
+/// Must only be called once.
+pub unsafe fn init(fdt: &DeviceTree<'_>) {
+    let manager = Box::leak(Box::new(DeviceManager::from_devicetree(fdt)));
+    DEVICE_MANAGER.store(manager, Ordering::Release);
+}
+
+/// Return the global device manager, if it was initialized already.
+pub fn manager() -> Option<&'static DeviceManager> {
+    unsafe { DEVICE_MANAGER.load(Ordering::Acquire).as_ref() }
+}
@@ -1,11 +1,21 @@
 //! Driver for the NS16550a UART Chip.
 
+use crate::{memmap::phys2virt, sync::WaitQueue};
+use alloc::collections::VecDeque;
 use core::{fmt, ptr::NonNull};
 use devicetree::node::Node;
+use riscv::sync::IrqMutex;
+
+/// The number of received bytes that are buffered, until they are read.
+const RX_BUFFER_SIZE: usize = 64;
 
 pub struct Device {
     interrupt_id: u32,
     base: NonNull<u8>,
+    /// The bytes that were received by the interrupt handler, but not read yet.
+    rx: IrqMutex<VecDeque<u8>>,
+    /// The threads that wait for received data.
+    readers: WaitQueue,
 }
 
 impl Device {
@@ -51,16 +61,17 @@ impl Device {
     ///
     /// Returns `None` if there's currently no data available.
     pub fn try_read(&self) -> Option<u8> {
-        self.data_ready().then(|| unsafe { self.read_data() })
+        self.rx.lock().pop_front().or_else(|| self.try_read_raw())
     }
 
-    /// Spins the hart until new data is available.
+    /// Puts the current thread to sleep until new data is available.
     pub fn read(&self) -> u8 {
-        while !self.data_ready() {}
+        self.readers.wait_until(|| self.try_read())
+    }
 
-        // SAFETY
-        // We only reach this code after data is ready
-        unsafe { self.read_data() }
+    /// Reads data directly from the receiver, bypassing the receive buffer.
+    fn try_read_raw(&self) -> Option<u8> {
+        self.data_ready().then(|| unsafe { self.read_data() })
     }
 
     /// Tries to write data into the transmitter.
@@ -151,10 +162,12 @@ impl super::DeviceDriver for Device {
     }
 
     fn from_node(node: &Node<'_>) -> Option<Self> {
-        let base = node.regions().next()?.start();
+        let base = phys2virt(node.regions().next()?.start());
         let uart = Device {
-            base: NonNull::new(base as *mut _)?,
+            base: NonNull::new(base.as_ptr())?,
             interrupt_id: node.prop("interrupts")?.as_u32()?,
+            rx: IrqMutex::new(VecDeque::with_capacity(RX_BUFFER_SIZE)),
+            readers: WaitQueue::new(),
         };
         Some(uart)
     }
@@ -174,11 +187,28 @@ impl super::DeviceDriver for Device {
 
 impl super::Interruptable for Device {
     fn handle_interrupt(&self, _: u32) -> Result<(), &'static str> {
-        // drain the input buffer
-        while let Some(c) = self.try_read() {
+        // drain the receiver into the buffer
+        let mut received = false;
+        while let Some(c) = self.try_read_raw() {
             if c == b'S' {
                 sbi::system::shutdown();
             }
+
+            #[cfg(feature = "lock-stats")]
+            if c == b'L' {
+                crate::lockstat::dump();
+            }
+
+            // drop the data if nobody reads it
+            let mut rx = self.rx.lock();
+            if rx.len() < RX_BUFFER_SIZE {
+                rx.push_back(c);
+                received = true;
+            }
+        }
+
+        if received {
+            self.readers.wake_all();
         }
 
         Ok(())
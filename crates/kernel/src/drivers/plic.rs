@@ -1,5 +1,6 @@
 //! Driver for the official RISC-V Program Level Interrupt Controller.
 
+use crate::memmap::phys2virt;
 use core::marker::PhantomData;
 use devicetree::node::Node;
 use voladdress::{Safe, VolAddress, VolBlock, VolSeries};
@@ -101,7 +102,7 @@ impl super::DeviceDriver for Controller {
     }
 
     fn from_node(node: &Node<'_>) -> Option<Self> {
-        let base = node.regions().next()?.start();
+        let base = usize::from(phys2virt(node.regions().next()?.start()));
         let max_interrupts = node.prop("riscv,ndev")?.as_u32()? as usize;
 
         unsafe {
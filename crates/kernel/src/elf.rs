@@ -0,0 +1,214 @@
+//! Parsing of ELF64 files, and loading them into a user address space.
+
+pub mod load;
+pub use load::{load, setup_stack, Image};
+
+use crate::{page, process};
+use core::{mem, ptr};
+
+pub const R_RISCV_NONE: usize = 0;
+pub const R_RISCV_RELATIVE: usize = 3;
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+pub const EM_RISCV: u16 = 243;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+pub const DT_NULL: usize = 0;
+pub const DT_RELA: usize = 7;
+pub const DT_RELASZ: usize = 8;
+pub const DT_RELAENT: usize = 9;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+
+/// Errors that can happen while parsing or loading an ELF file.
+#[derive(Debug)]
+pub enum Error {
+    /// The file is too small, or some header points outside of the file.
+    Truncated,
+    /// The file doesn't start with the ELF magic.
+    InvalidMagic,
+    /// The file is not a little endian, 64-bit RISC-V file.
+    UnsupportedArch,
+    /// The file is neither an executable nor a position independent executable.
+    UnsupportedType(u16),
+    /// A segment is malformed, or doesn't fit into the user address space.
+    InvalidSegment,
+    /// The file requests a program interpreter, which is not supported.
+    DynamicallyLinked,
+    /// The file contains a relocation other than `R_RISCV_RELATIVE`.
+    UnsupportedRelocation(usize),
+    Page(page::Error),
+    Fault(process::Fault),
+}
+
+/// The ELF file header.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Header {
+    pub ident: [u8; 16],
+    pub kind: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: usize,
+    pub phoff: usize,
+    pub shoff: usize,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+/// An entry inside the program header table, which describes a segment.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: usize,
+    pub vaddr: usize,
+    pub paddr: usize,
+    pub filesz: usize,
+    pub memsz: usize,
+    pub align: usize,
+}
+
+/// An entry inside the dynamic section.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Dyn {
+    pub tag: usize,
+    pub val: usize,
+}
+
+/// A relocation entry with an addend.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Rela {
+    pub offset: usize,
+    pub info: usize,
+    pub addend: isize,
+}
+
+impl Rela {
+    /// Return the relocation type of this entry.
+    pub fn kind(&self) -> usize {
+        self.info & 0xFFFF_FFFF
+    }
+}
+
+/// A parsed ELF file, that was validated to be a RISC-V 64 executable.
+pub struct Elf<'data> {
+    data: &'data [u8],
+    header: Header,
+}
+
+impl<'data> Elf<'data> {
+    /// Parse and validate the header of the given ELF file.
+    pub fn parse(data: &'data [u8]) -> Result<Self, Error> {
+        let header = read::<Header>(data, 0)?;
+
+        if header.ident[..4] != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+
+        if header.ident[4] != CLASS_64
+            || header.ident[5] != DATA_LITTLE_ENDIAN
+            || header.machine != EM_RISCV
+        {
+            return Err(Error::UnsupportedArch);
+        }
+
+        if header.kind != ET_EXEC && header.kind != ET_DYN {
+            return Err(Error::UnsupportedType(header.kind));
+        }
+
+        if header.phentsize as usize != mem::size_of::<ProgramHeader>() {
+            return Err(Error::Truncated);
+        }
+
+        let elf = Self { data, header };
+
+        // make sure the whole program header table is inside the file
+        elf.program_headers().try_for_each(|ph| ph.map(|_| ()))?;
+
+        Ok(elf)
+    }
+
+    /// Return the file header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Check if this is a position independent executable.
+    pub fn is_pie(&self) -> bool {
+        self.header.kind == ET_DYN
+    }
+
+    /// Return an iterator over every program header.
+    pub fn program_headers(&self) -> impl Iterator<Item = Result<ProgramHeader, Error>> + '_ {
+        (0..self.header.phnum as usize).map(move |idx| {
+            let off = idx * mem::size_of::<ProgramHeader>();
+            read(
+                self.data,
+                self.header.phoff.checked_add(off).ok_or(Error::Truncated)?,
+            )
+        })
+    }
+
+    /// Return an iterator over every program header that was already validated by [`Self::parse`].
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        self.program_headers().filter_map(Result::ok)
+    }
+
+    /// Return the bytes of the given segment inside the file.
+    pub fn segment_data(&self, ph: &ProgramHeader) -> Result<&'data [u8], Error> {
+        let end = ph.offset.checked_add(ph.filesz).ok_or(Error::Truncated)?;
+        self.data.get(ph.offset..end).ok_or(Error::Truncated)
+    }
+
+    /// Translate a virtual address, as it's stored inside the file, into
+    /// an offset inside the file.
+    pub fn vaddr_to_offset(&self, vaddr: usize) -> Option<usize> {
+        self.segments()
+            .filter(|ph| ph.kind == PT_LOAD)
+            .find(|ph| vaddr >= ph.vaddr && vaddr - ph.vaddr < ph.filesz)
+            .map(|ph| vaddr - ph.vaddr + ph.offset)
+    }
+
+    /// Translate an offset inside the file into the virtual address it's loaded at,
+    /// as it's stored inside the file.
+    pub fn vaddr_of_offset(&self, offset: usize) -> Option<usize> {
+        self.segments()
+            .filter(|ph| ph.kind == PT_LOAD)
+            .find(|ph| offset >= ph.offset && offset - ph.offset < ph.filesz)
+            .map(|ph| offset - ph.offset + ph.vaddr)
+    }
+
+    /// Read a `T` from the file at the given offset.
+    pub fn read<T: Copy>(&self, offset: usize) -> Result<T, Error> {
+        read(self.data, offset)
+    }
+}
+
+fn read<T: Copy>(data: &[u8], offset: usize) -> Result<T, Error> {
+    let end = offset
+        .checked_add(mem::size_of::<T>())
+        .ok_or(Error::Truncated)?;
+    let bytes = data.get(offset..end).ok_or(Error::Truncated)?;
+    Ok(unsafe { ptr::read_unaligned(bytes.as_ptr().cast::<T>()) })
+}
@@ -0,0 +1,330 @@
+//! Loading ELF executables into a user address space.
+//!
+//! Every segment becomes a file backed [`Vma`], so the pages are only read from the file
+//! when they are accessed. Pages that have to be relocated are loaded right away.
+
+use super::{
+    Dyn, Elf, Error, ProgramHeader, Rela, DT_NULL, DT_RELA, DT_RELAENT, DT_RELASZ, PF_R, PF_W,
+    PF_X, PT_DYNAMIC, PT_INTERP, PT_LOAD, PT_PHDR, R_RISCV_NONE, R_RISCV_RELATIVE,
+};
+use crate::{
+    allocator::{align_up, PAGE_SIZE},
+    memmap::{self, USER_STACK_SIZE},
+    page::{Flags, VirtAddr},
+    process::{self, AddressSpace, Vma, VmaKind},
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::{cmp, mem};
+
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_BASE: usize = 7;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+/// Information about an executable that was loaded into an address space.
+#[derive(Debug, Clone, Copy)]
+pub struct Image {
+    /// The address the executable was loaded at, which is `0` if it's not position independent.
+    pub base: usize,
+    /// The entrypoint of the executable.
+    pub entry: VirtAddr,
+    /// The address of the program header table, if it's part of a loaded segment.
+    pub phdr: Option<VirtAddr>,
+    /// The number of program headers.
+    pub phnum: usize,
+}
+
+/// Load every segment of the executable into the given address space.
+///
+/// `file` must contain the same data that `elf` was parsed from.
+/// Position independent executables are loaded at `base` and relocated,
+/// all other executables are loaded at the addresses they were linked at.
+pub fn load(
+    space: &mut AddressSpace,
+    elf: &Elf<'_>,
+    file: &Arc<[u8]>,
+    base: usize,
+) -> Result<Image, Error> {
+    if elf.segments().any(|ph| ph.kind == PT_INTERP) {
+        return Err(Error::DynamicallyLinked);
+    }
+
+    let base = if elf.is_pie() { base } else { 0 };
+
+    for ph in elf.segments().filter(|ph| ph.kind == PT_LOAD) {
+        load_segment(space, elf, file, &ph, base)?;
+    }
+
+    if elf.is_pie() {
+        relocate(space, elf, base)?;
+    }
+
+    // the program headers are either described by their own segment,
+    // or we have to search for the segment that contains them
+    let phoff = elf.header().phoff;
+    let phdr = elf
+        .segments()
+        .find(|ph| ph.kind == PT_PHDR)
+        .map(|ph| ph.vaddr)
+        .or_else(|| elf.vaddr_of_offset(phoff))
+        .map(|vaddr| VirtAddr::from(base + vaddr));
+
+    Ok(Image {
+        base,
+        entry: VirtAddr::from(base.wrapping_add(elf.header().entry)),
+        phdr,
+        phnum: elf.header().phnum as usize,
+    })
+}
+
+fn load_segment(
+    space: &mut AddressSpace,
+    elf: &Elf<'_>,
+    file: &Arc<[u8]>,
+    ph: &ProgramHeader,
+    base: usize,
+) -> Result<(), Error> {
+    let data = elf.segment_data(ph)?;
+    if ph.filesz > ph.memsz {
+        return Err(Error::InvalidSegment);
+    }
+
+    if ph.memsz == 0 {
+        return Ok(());
+    }
+
+    // make sure the segment is inside the user half of the address space
+    let start = base.checked_add(ph.vaddr).ok_or(Error::InvalidSegment)?;
+    let end = start.checked_add(ph.memsz).ok_or(Error::InvalidSegment)?;
+    if end > memmap::layout().user_space_end() {
+        return Err(Error::InvalidSegment);
+    }
+
+    let flags = segment_flags(ph);
+    let mut page = start & !(PAGE_SIZE - 1);
+    let page_end = align_up(end, PAGE_SIZE);
+
+    // the first page may be shared with the previous segment. it's loaded right away,
+    // and gets the permissions of both segments, as long as it doesn't become
+    // writable and executable at the same time.
+    if let Some(prev) = space.vmas().find(page) {
+        let both = prev.flags() | flags;
+        if both.contains(Flags::WRITE | Flags::EXEC) {
+            return Err(Error::InvalidSegment);
+        }
+
+        let len = cmp::min(PAGE_SIZE - start % PAGE_SIZE, ph.memsz);
+        space.populate(page, PAGE_SIZE).map_err(Error::Fault)?;
+        space.extend_flags(page, flags).map_err(Error::Fault)?;
+
+        let filesz = cmp::min(len, ph.filesz);
+        copy_to_user(space, start, &data[..filesz])?;
+        zero_user(space, start + filesz, len - filesz)?;
+
+        page += PAGE_SIZE;
+        if page >= page_end {
+            return Ok(());
+        }
+    }
+
+    // either the segment starts `skip` bytes into the area, or the first
+    // `consumed` bytes of the segment were already loaded into the shared page
+    let (skip, consumed) = if page <= start {
+        (start - page, 0)
+    } else {
+        (0, page - start)
+    };
+
+    let kind = VmaKind::File {
+        data: Arc::clone(file),
+        offset: ph.offset + consumed.min(ph.filesz),
+        skip,
+        size: ph.filesz.saturating_sub(consumed),
+    };
+    space
+        .map(Vma::new(page, page_end, flags, kind))
+        .map_err(Error::Page)
+}
+
+/// Convert the permissions of a segment to page flags.
+fn segment_flags(ph: &ProgramHeader) -> Flags {
+    let mut flags = Flags::empty();
+    if ph.flags & PF_R != 0 {
+        flags |= Flags::READ;
+    }
+    if ph.flags & PF_W != 0 {
+        flags |= Flags::WRITE;
+    }
+    if ph.flags & PF_X != 0 {
+        flags |= Flags::EXEC;
+    }
+    flags
+}
+
+/// Apply all relocations of a position independent executable that was loaded at `base`.
+fn relocate(space: &mut AddressSpace, elf: &Elf<'_>, base: usize) -> Result<(), Error> {
+    let dynamic = match elf.segments().find(|ph| ph.kind == PT_DYNAMIC) {
+        Some(ph) => ph,
+        None => return Ok(()),
+    };
+
+    // find the relocation table inside the dynamic section
+    let (mut rela, mut relasz, mut relaent) = (None, 0, mem::size_of::<Rela>());
+    let end = dynamic.offset.saturating_add(dynamic.filesz);
+    for off in (dynamic.offset..end).step_by(mem::size_of::<Dyn>()) {
+        let entry = elf.read::<Dyn>(off)?;
+        match entry.tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(entry.val),
+            DT_RELASZ => relasz = entry.val,
+            DT_RELAENT => relaent = entry.val,
+            _ => {}
+        }
+    }
+
+    let start = match rela {
+        Some(vaddr) => elf.vaddr_to_offset(vaddr).ok_or(Error::Truncated)?,
+        None => return Ok(()),
+    };
+
+    if relaent != mem::size_of::<Rela>() {
+        return Err(Error::Truncated);
+    }
+
+    for off in (start..start.saturating_add(relasz)).step_by(relaent) {
+        let entry = elf.read::<Rela>(off)?;
+
+        // this is the same relocation that is applied to the kernel in `boot::reloc`
+        match entry.kind() {
+            R_RISCV_NONE => {}
+            R_RISCV_RELATIVE => {
+                let addr = base
+                    .checked_add(entry.offset)
+                    .ok_or(Error::InvalidSegment)?;
+                let end = addr
+                    .checked_add(mem::size_of::<usize>())
+                    .ok_or(Error::InvalidSegment)?;
+
+                // the target must be inside a loaded segment. their bounds were already
+                // checked by `load_segment`, so they can't overflow
+                let loaded = elf.segments().filter(|ph| ph.kind == PT_LOAD).any(|ph| {
+                    let start = base + ph.vaddr;
+                    start <= addr && end <= start + ph.memsz
+                });
+                if !loaded {
+                    return Err(Error::InvalidSegment);
+                }
+
+                let value = base.wrapping_add(entry.addend as usize);
+                copy_to_user(space, addr, &value.to_le_bytes())?;
+            }
+            kind => return Err(Error::UnsupportedRelocation(kind)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Map the user stack together with a guard page below it, and push the arguments,
+/// environment and auxiliary vector onto it, as described by the System V ABI.
+///
+/// Returns the initial stack pointer.
+pub fn setup_stack(
+    space: &mut AddressSpace,
+    image: &Image,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, Error> {
+    let top = memmap::layout().user_stack_top();
+    let bottom = top - USER_STACK_SIZE - PAGE_SIZE;
+    space
+        .map(Vma::new(
+            bottom,
+            top,
+            Flags::READ | Flags::WRITE,
+            VmaKind::Stack,
+        ))
+        .map_err(Error::Page)?;
+
+    // the auxiliary vector, without the terminating entry
+    let mut auxv = Vec::new();
+    if let Some(phdr) = image.phdr {
+        auxv.push((AT_PHDR, usize::from(phdr)));
+    }
+    auxv.push((AT_PHENT, mem::size_of::<ProgramHeader>()));
+    auxv.push((AT_PHNUM, image.phnum));
+    auxv.push((AT_PAGESZ, PAGE_SIZE));
+    auxv.push((AT_BASE, 0));
+    auxv.push((AT_ENTRY, usize::from(image.entry)));
+
+    // make sure everything fits onto the stack
+    let strings = argv.iter().chain(envp).map(|s| s.len() + 1).sum::<usize>();
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * (auxv.len() + 2);
+    if strings + 16 + words * mem::size_of::<usize>() + 16 > USER_STACK_SIZE {
+        return Err(Error::InvalidSegment);
+    }
+
+    // the strings are stored at the top of the stack
+    let mut sp = top;
+    let mut push_str = |s: &str| {
+        sp -= s.len() + 1;
+        copy_to_user(space, sp, s.as_bytes())?;
+        copy_to_user(space, sp + s.len(), &[0])?;
+        Ok::<_, Error>(sp)
+    };
+
+    let argv = argv
+        .iter()
+        .map(|s| push_str(s))
+        .collect::<Result<Vec<_>, _>>()?;
+    let envp = envp
+        .iter()
+        .map(|s| push_str(s))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // the random bytes are used by the C library, for example as the stack protector canary.
+    // they are not cryptographically secure.
+    let random = riscv::asm::rdcycle() ^ riscv::asm::rdtime().rotate_left(32);
+    let random = [random, random.rotate_left(17) ^ riscv::asm::rdinstret()];
+    sp -= mem::size_of_val(&random);
+    for (idx, word) in random.iter().enumerate() {
+        copy_to_user(space, sp + idx * 8, &word.to_le_bytes())?;
+    }
+    auxv.push((AT_RANDOM, sp));
+    auxv.push((AT_NULL, 0));
+
+    // build the argc, argv, envp and auxv block, which is placed at the stack pointer
+    let mut block = Vec::with_capacity(words);
+    block.push(argv.len());
+    block.extend(argv.iter().copied().chain(Some(0)));
+    block.extend(envp.iter().copied().chain(Some(0)));
+    block.extend(auxv.iter().flat_map(|&(key, val)| [key, val]));
+
+    // the stack pointer must be 16 byte aligned
+    sp = (sp - block.len() * mem::size_of::<usize>()) & !0xF;
+    for (idx, word) in block.iter().enumerate() {
+        copy_to_user(space, sp + idx * 8, &word.to_le_bytes())?;
+    }
+
+    Ok(VirtAddr::from(sp))
+}
+
+/// Copy `data` into the user memory at `vaddr`, ignoring the permissions of the pages.
+fn copy_to_user(space: &mut AddressSpace, vaddr: usize, data: &[u8]) -> Result<(), Error> {
+    space.populate(vaddr, data.len()).map_err(Error::Fault)?;
+    process::with_user_memory(space.table(), vaddr, data.len(), Flags::USER, |mem, off| {
+        mem.copy_from_slice(&data[off..off + mem.len()])
+    })
+    .map_err(|_| Error::InvalidSegment)
+}
+
+/// Zero the user memory at `vaddr`, ignoring the permissions of the pages.
+fn zero_user(space: &mut AddressSpace, vaddr: usize, len: usize) -> Result<(), Error> {
+    space.populate(vaddr, len).map_err(Error::Fault)?;
+    process::with_user_memory(space.table(), vaddr, len, Flags::USER, |mem, _| mem.fill(0))
+        .map_err(|_| Error::InvalidSegment)
+}
@@ -1,20 +1,36 @@
 //! Hart local storage and context.
 
-use crate::drivers::plic;
+use crate::drivers::{self, plic, DeviceManager};
 use crate::{
-    allocator, memmap,
-    pmem::{self, Box, Vec},
-    unit,
+    allocator::{self, PAGE_SIZE},
+    memmap::{self, EMERGENCY_STACK_SIZE, TRAP_STACK_SIZE},
+    page::{self, Flags, PageSize},
+    pmem::{self, Box},
+    thread::Scheduler,
+    time::TimerQueue,
 };
-use core::mem::ManuallyDrop;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 use devicetree::DeviceTree;
+use riscv::sync::IrqMutex;
 
-/// The size of each trap stack.
-pub const TRAP_STACK_SIZE: usize = 4 * unit::KIB;
+/// Bitmask of all harts that finished their initialization and reported themselves online.
+static ONLINE_HARTS: AtomicU64 = AtomicU64::new(0);
+
+/// The maximum number of harts, limited by the size of [`ONLINE_HARTS`].
+pub const MAX_HARTS: usize = 64;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CONTEXT: AtomicPtr<HartContext> = AtomicPtr::new(core::ptr::null_mut());
+
+/// The contexts of all harts, indexed by their id.
+static CONTEXTS: [AtomicPtr<HartContext>; MAX_HARTS] = [NO_CONTEXT; MAX_HARTS];
 
 /// This structure is replicated on every hart and stores
 /// hart-local information like a trap-stack or the hart id.
+///
+/// The fields up to `emergency_stack` are accessed by the trap handler,
+/// so keep their order in sync with `_trap_handler`.
 #[repr(C)]
 pub struct HartContext {
     /// The id of this hart, this is our own generated id and is not compatible
@@ -23,11 +39,27 @@ pub struct HartContext {
     /// A pointer to the stack that must be used during interrupts. This pointer will point to
     /// the end of the trap stack inside virtual memory space.
     trap_stack: NonNull<u8>,
-    /// Location to temporarily store the stack pointer inside the interrupt handler.
-    temp_sp: usize,
+    /// Location to temporarily store two registers inside the trap handler,
+    /// before it found a stack to use.
+    scratch: [usize; 2],
+    /// The thread pointer of the kernel, which is restored by the trap handler
+    /// if a trap comes from user mode.
+    kernel_tp: AtomicUsize,
+    /// The end of the stack, that is used by the trap handler if the kernel overflowed its stack.
+    emergency_stack: usize,
     /// The hart id of the hart that booted up the kernel.
     bsp_id: u64,
     fdt: DeviceTree<'static>,
+    /// The queue of all timers that are armed on this hart.
+    timers: IrqMutex<TimerQueue>,
+    /// The run queue of all threads on this hart.
+    scheduler: IrqMutex<Scheduler>,
+    /// The `pc` and frame pointer of the kernel code that was interrupted by the trap
+    /// that is currently handled, or zero if there's no such trap.
+    trap_pc: AtomicUsize,
+    trap_fp: AtomicUsize,
+    /// The number of traps that are currently handled on this hart.
+    trap_depth: AtomicUsize,
 }
 
 impl HartContext {
@@ -55,6 +87,62 @@ impl HartContext {
         self.fdt
     }
 
+    /// Get access to the global device manager, if it was initialized already.
+    #[inline]
+    pub fn devices(&self) -> Option<&'static DeviceManager> {
+        drivers::manager::manager()
+    }
+
+    /// Get access to the timer queue of this hart.
+    #[inline]
+    pub(crate) fn timers(&self) -> &IrqMutex<TimerQueue> {
+        &self.timers
+    }
+
+    /// Get access to the scheduler of this hart.
+    #[inline]
+    pub(crate) fn scheduler(&self) -> &IrqMutex<Scheduler> {
+        &self.scheduler
+    }
+
+    /// Remember the kernel code that was interrupted by the current trap,
+    /// or forget it if `None` is given.
+    #[inline]
+    pub(crate) fn set_trap_origin(&self, origin: Option<(usize, usize)>) {
+        let (pc, fp) = origin.unwrap_or((0, 0));
+        self.trap_fp.store(fp, Ordering::Relaxed);
+        self.trap_pc.store(pc, Ordering::Relaxed);
+    }
+
+    /// Return the `pc` and frame pointer of the kernel code that was interrupted by
+    /// the trap that is currently handled on this hart.
+    #[inline]
+    pub fn trap_origin(&self) -> Option<(usize, usize)> {
+        match self.trap_pc.load(Ordering::Relaxed) {
+            0 => None,
+            pc => Some((pc, self.trap_fp.load(Ordering::Relaxed))),
+        }
+    }
+
+    /// Record that a trap is handled on this hart, and return
+    /// the number of traps that were already being handled.
+    #[inline]
+    pub(crate) fn enter_trap(&self) -> usize {
+        self.trap_depth.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Record that the innermost trap on this hart was handled.
+    #[inline]
+    pub(crate) fn leave_trap(&self) {
+        self.trap_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Check if this hart is currently handling a trap.
+    #[inline]
+    pub fn in_trap(&self) -> bool {
+        self.trap_depth.load(Ordering::Relaxed) != 0
+    }
+
     /// Get the PLIC context for the current hart.
     pub fn plic_context(&self) -> plic::Context {
         let raw = 1 + 2 * self.id;
@@ -74,31 +162,71 @@ pub fn try_current() -> Option<&'static HartContext> {
     (addr != 0).then(|| unsafe { &*(addr as *const _) })
 }
 
+/// Get the context of the hart with the given id, if it was initialized already.
+pub fn get(id: u64) -> Option<&'static HartContext> {
+    let ptr = CONTEXTS.get(id as usize)?.load(Ordering::Acquire);
+    unsafe { ptr.as_ref() }
+}
+
 /// Get the context for the current hart.
 pub fn current() -> &'static HartContext {
     try_current().expect("Hart local context not yet initialized")
 }
 
+/// Mark the current hart as online, so other harts can see that it finished initialization.
+pub fn mark_online() {
+    ONLINE_HARTS.fetch_or(1 << current().id(), Ordering::AcqRel);
+}
+
+/// Check if the hart with the given id has reported itself as online.
+pub fn is_online(id: u64) -> bool {
+    ONLINE_HARTS.load(Ordering::Acquire) & (1 << id) != 0
+}
+
+/// Allocate and map the stack for this hart inside the stack region at `base`,
+/// and return the end of the new stack.
+fn map_stack(base: usize, size: usize, hart_id: u64) -> Result<usize, page::Error> {
+    let start = memmap::stack_start(base, size, hart_id as usize);
+
+    page::root().map_alloc(
+        start.into(),
+        size / PAGE_SIZE,
+        PageSize::Kilopage,
+        Flags::READ | Flags::WRITE | Flags::ACCESSED | Flags::DIRTY,
+    )?;
+
+    Ok(start + size)
+}
+
 /// Initializes the context for this hart by allocating memory and then saving
 /// the pointer inside the `sscratch` CSR.
 pub unsafe fn init_hart_context(
     hart_id: u64,
     bsp_id: u64,
     fdt: DeviceTree<'static>,
-) -> Result<(), allocator::Error> {
-    // allocate the trap stack
-    let mut trap_stack = ManuallyDrop::new(Vec::<u8>::with_capacity_in(
-        TRAP_STACK_SIZE,
-        pmem::GlobalPhysicalAllocator,
-    ));
+) -> Result<(), page::Error> {
+    // allocate the trap and emergency stack, which both have a guard page below them
+    let trap_stack = map_stack(memmap::layout().trap_stack_base, TRAP_STACK_SIZE, hart_id)?;
+    let emergency_stack = map_stack(
+        memmap::layout().emergency_stack_base,
+        EMERGENCY_STACK_SIZE,
+        hart_id,
+    )?;
 
     // create the hart context and write it to the page
     let ctx = HartContext {
         id: hart_id,
-        trap_stack: NonNull::new(trap_stack.as_mut_ptr()).unwrap(),
-        temp_sp: 0,
+        trap_stack: NonNull::new(trap_stack as *mut u8).unwrap(),
+        scratch: [0; 2],
+        kernel_tp: AtomicUsize::new(0),
+        emergency_stack,
         bsp_id,
         fdt,
+        timers: IrqMutex::new(TimerQueue::new()),
+        scheduler: IrqMutex::new(Scheduler::new()),
+        trap_pc: AtomicUsize::new(0),
+        trap_fp: AtomicUsize::new(0),
+        trap_depth: AtomicUsize::new(0),
     };
 
     // box up the context so it's stored on the heap
@@ -108,6 +236,12 @@ pub unsafe fn init_hart_context(
     // available everywhere on this hart
     asm!("csrw sscratch, {}", in(reg) ptr);
 
+    // and make it available to other harts
+    CONTEXTS
+        .get(hart_id as usize)
+        .expect("hart id is too large")
+        .store(ptr, Ordering::Release);
+
     Ok(())
 }
 
@@ -128,6 +262,9 @@ pub unsafe fn init_hart_local_storage() -> Result<(), allocator::Error> {
 
     // set the thread pointer register
     asm!("mv tp, {}", in(reg) new.as_ptr());
+    current()
+        .kernel_tp
+        .store(new.as_ptr() as usize, Ordering::Relaxed);
 
     Ok(())
 }
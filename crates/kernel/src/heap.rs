@@ -0,0 +1,168 @@
+//! The kernel heap, which is used as the global allocator.
+//!
+//! Small allocations are served by a [`SlabAllocator`], and everything that is too large
+//! for the slabs is directly mapped as a range of pages. All memory of the heap lives
+//! in the virtual memory region starting at
+//! [`vmem_alloc_base`](crate::memmap::Layout::vmem_alloc_base).
+
+use crate::{
+    allocator::{self, align_up, AllocStats, SlabAllocator, PAGE_SIZE},
+    memmap,
+    page::{self, Flags, PageSize, PhysAddr, VirtAddr},
+    pmem,
+};
+use core::alloc::{GlobalAlloc, Layout};
+use core::{cmp, ptr::NonNull};
+use riscv::sync::IrqMutex;
+
+#[global_allocator]
+static HEAP: GlobalHeap = GlobalHeap(IrqMutex::new(Heap::new()));
+
+/// The flags that are used to map memory of the heap.
+const HEAP_FLAGS: Flags = Flags::from_bits_truncate(
+    Flags::READ.bits() | Flags::WRITE.bits() | Flags::ACCESSED.bits() | Flags::DIRTY.bits(),
+);
+
+struct Heap {
+    slab: SlabAllocator,
+    /// The number of bytes of virtual memory, that were reserved so far.
+    reserved: usize,
+    /// The number of bytes that were allocated by bypassing the slabs.
+    large: usize,
+}
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            slab: SlabAllocator::new(),
+            reserved: 0,
+            large: 0,
+        }
+    }
+
+    /// Reserve a range of virtual memory with the given size and alignment.
+    ///
+    /// Virtual memory is never given back, because the heap region is large enough
+    /// to never run out of addresses.
+    fn reserve(&mut self, size: usize, align: usize) -> VirtAddr {
+        let base = memmap::layout().vmem_alloc_base;
+        let start = align_up(base + self.reserved, cmp::max(align, PAGE_SIZE));
+        self.reserved = start + align_up(size, PAGE_SIZE) - base;
+        VirtAddr::from(start)
+    }
+
+    fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, allocator::Error> {
+        match self.slab.allocate(layout) {
+            // the slab for this layout is empty, so we give it a new page and try again
+            Err(allocator::Error::NoMemoryAvailable) => {
+                let page = pmem::alloc()?;
+                let vaddr = self.reserve(PAGE_SIZE, PAGE_SIZE);
+
+                let mapped = page::root().map(
+                    PhysAddr::from(page.as_ptr()),
+                    vaddr,
+                    PageSize::Kilopage,
+                    HEAP_FLAGS,
+                );
+
+                // give the page back if we failed to map it
+                if mapped.is_err() {
+                    unsafe { pmem::free(page)? };
+                    return Err(allocator::Error::NoMemoryAvailable);
+                }
+
+                unsafe {
+                    self.slab
+                        .grow(layout, NonNull::new(vaddr.as_ptr()).unwrap())?;
+                }
+                self.slab.allocate(layout)
+            }
+            // the layout is too large for the slabs, so we map the pages directly
+            Err(allocator::Error::NoSlabForLayout) => {
+                let count = align_up(layout.size(), PAGE_SIZE) / PAGE_SIZE;
+                let vaddr = self.reserve(layout.size(), layout.align());
+
+                page::root()
+                    .map_alloc(vaddr, count, PageSize::Kilopage, HEAP_FLAGS)
+                    .map_err(|_| allocator::Error::NoMemoryAvailable)?;
+
+                self.large += count * PAGE_SIZE;
+                NonNull::new(vaddr.as_ptr()).ok_or(allocator::Error::NullPointer)
+            }
+            res => res,
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) -> allocator::Result<()> {
+        match self.slab.deallocate(ptr, layout) {
+            Err(allocator::Error::NoSlabForLayout) => {
+                let count = align_up(layout.size(), PAGE_SIZE) / PAGE_SIZE;
+
+                page::root()
+                    .free(ptr.as_ptr().into(), count)
+                    .map_err(|_| allocator::Error::InvalidRegion)?;
+
+                self.large -= count * PAGE_SIZE;
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    fn stats(&self) -> AllocStats {
+        let mut stats = self.slab.stats();
+        stats.allocated += self.large;
+        stats.total += self.large;
+        stats
+    }
+}
+
+/// Return the lock statistics of the kernel heap.
+#[cfg(feature = "lock-stats")]
+pub(crate) fn lock_stats() -> &'static riscv::sync::stats::LockStats {
+    HEAP.0.stats()
+}
+
+/// The global allocator that allocates memory from the kernel heap.
+///
+/// Growing the heap requires access to the [global page table](page::root),
+/// so the heap must not be used while holding the page table lock.
+struct GlobalHeap(IrqMutex<Heap>);
+
+unsafe impl GlobalAlloc for GlobalHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.0.lock().allocate(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(err) => {
+                log::warn!(
+                    "{} to allocate {} bytes on the heap: {:?}",
+                    "Failed".yellow(),
+                    layout.size(),
+                    err
+                );
+                core::ptr::null_mut()
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = match NonNull::new(ptr) {
+            Some(ptr) => ptr,
+            None => return,
+        };
+
+        if let Err(err) = self.0.lock().deallocate(ptr, layout) {
+            log::warn!(
+                "{} to free {} bytes on the heap: {:?}",
+                "Failed".yellow(),
+                layout.size(),
+                err
+            );
+        }
+    }
+}
+
+/// Return the statistics of the kernel heap.
+pub fn alloc_stats() -> AllocStats {
+    HEAP.0.lock().stats()
+}
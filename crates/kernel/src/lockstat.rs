@@ -0,0 +1,53 @@
+//! Contention statistics of the global kernel locks.
+//!
+//! Only compiled with the `lock-stats` feature. Pressing `L` on the serial console
+//! prints the counters of every registered lock.
+
+use crate::{boot, heap, page, pmem, process, thread};
+use riscv::sync::stats;
+
+/// Register all global locks of the kernel.
+pub fn init() {
+    let locks = [
+        ("PAGE_TABLE", boot::PAGE_TABLE.stats()),
+        ("PHYS_MEM", pmem::lock_stats()),
+        ("HEAP", heap::lock_stats()),
+        ("ASID", page::asid::lock_stats()),
+        ("SHARED_PAGES", process::mm::lock_stats()),
+        ("STACK_SLOTS", thread::lock_stats()),
+    ];
+
+    for (name, lock) in locks {
+        if !stats::register(name, lock) {
+            log::warn!(
+                "{} to register lock statistics of {}",
+                "Failed".yellow(),
+                name
+            );
+        }
+    }
+}
+
+/// Print the counters of every registered lock.
+pub fn dump() {
+    log::info!(
+        "{:<16} {:>12} {:>10} {:>14} {:>12}",
+        "lock",
+        "acquired",
+        "contended",
+        "spins",
+        "max hold"
+    );
+
+    stats::for_each(|name, snap| {
+        log::info!(
+            "{:<16} {:>12} {:>10} {:>14} {:>9}.{:03}ms",
+            name,
+            snap.acquisitions,
+            snap.contended,
+            snap.spins,
+            snap.max_hold.as_millis(),
+            snap.max_hold.subsec_micros() % 1000,
+        );
+    });
+}
@@ -10,20 +10,32 @@
     allocator_api,
     fn_align,
     thread_local,
-    vec_into_raw_parts
+    vec_into_raw_parts,
+    const_btree_new
 )]
 #![allow(clippy::missing_safety_doc, clippy::empty_loop)]
 
 extern crate alloc;
 
 pub mod allocator;
+pub mod backtrace;
 pub mod boot;
 pub mod drivers;
+pub mod elf;
 pub mod hart;
+pub mod heap;
+#[cfg(feature = "lock-stats")]
+pub mod lockstat;
 pub mod memmap;
 pub mod page;
 pub mod pmem;
+pub mod process;
 pub mod symbols;
+pub mod sync;
+pub mod syscall;
+pub mod thread;
+pub mod time;
+pub mod tlb;
 pub mod trap;
 pub mod unit;
 
@@ -53,6 +65,12 @@ pub fn main(fdt: &DeviceTree<'_>) -> ! {
     log_core_online();
 
     log::debug!("{}", pmem::alloc_stats());
+    log::debug!("{}", heap::alloc_stats());
+    log::debug!(
+        "{:?} paging, {}",
+        <page::KernelMode as page::PagingMode>::satp_mode(),
+        page::root().stats()
+    );
 
     sbi::system::shutdown()
 }
@@ -123,18 +141,6 @@ fn log_core_online() {
         isa.magenta(),
         arch.blue(),
     );
-}
-
-use alloc::alloc::{GlobalAlloc, Layout};
 
-struct MyAllocator;
-
-unsafe impl GlobalAlloc for MyAllocator {
-    unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
-        core::ptr::null_mut()
-    }
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    hart::mark_online();
 }
-
-#[global_allocator]
-static A: MyAllocator = MyAllocator;
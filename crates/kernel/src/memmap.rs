@@ -1,24 +1,174 @@
 //! Module for working with the virtual memory map of the kernel.
 
-use crate::page::{PhysAddr, VirtAddr};
+use crate::{
+    page::{PhysAddr, VirtAddr},
+    StaticCell,
+};
 use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::csr::satp;
 
-/// The address at which the higher half of the address space begins, and which is used as the base
-/// for everything.
-pub const HIGHER_HALF_START: usize = 0x4000_0000_0000;
+/// The stack size for the main thread of a process, without the guard page below the stack.
+pub const USER_STACK_SIZE: usize = 256 * 1024;
 
-/// The virtual address at which the physical memory is mapped in, such that adding
-/// this constant to any "real" physaddr returns the new physaddr which can be used if
-/// paging is activaed.
-pub const KERNEL_PHYS_MEM_BASE: usize = HIGHER_HALF_START + 0x0A00_0000_0000;
+/// The address position independent executables are loaded at.
+pub const USER_PIE_BASE: usize = 0x1000_0000;
+
+/// The size of the unmapped guard page below every kernel stack.
+pub const STACK_GUARD_SIZE: usize = 4 * 1024;
 
-/// The base virtual addresses where the stack for every hart is located.
-pub const KERNEL_STACK_BASE: usize = HIGHER_HALF_START + 0x0B00_0000_0000;
 /// The stack size for each hart.
 pub const KERNEL_STACK_SIZE: usize = 1024 * 1024;
+/// The size of the stack, that is used by the trap handler of each hart.
+pub const TRAP_STACK_SIZE: usize = 4 * 1024;
+/// The size of the stack that is used to report a stack overflow.
+pub const EMERGENCY_STACK_SIZE: usize = 16 * 1024;
+/// The stack size for each kernel thread.
+pub const THREAD_STACK_SIZE: usize = 64 * 1024;
+
+/// The virtual memory layout of every address space, which depends on the paging mode.
+///
+/// The first five fields are read by the trap entry assembly, so keep their order in sync.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    /// The base virtual addresses where the stack for every hart is located.
+    ///
+    /// All kernel stacks are located between this address and the
+    /// [`vmem_alloc_base`](Self::vmem_alloc_base).
+    pub kernel_stack_base: usize,
+    /// The base virtual address where the trap stack of every hart is located.
+    pub trap_stack_base: usize,
+    /// The base virtual address where the emergency stack of every hart is located.
+    pub emergency_stack_base: usize,
+    /// The base virtual address where the stacks of kernel threads are located.
+    pub thread_stack_base: usize,
+    /// The base virtual address where the allocator will start allocating virtual memory.
+    pub vmem_alloc_base: usize,
+    /// The address at which the higher half of the address space begins, and where
+    /// the kernel is mapped.
+    pub higher_half_start: usize,
+    /// The virtual address at which the physical memory is mapped in, such that adding
+    /// this address to any "real" physaddr returns the new physaddr which can be used if
+    /// paging is activaed.
+    pub phys_mem_base: usize,
+    /// The address at which anonymous memory, that was requested using `mmap`, is placed.
+    pub user_mmap_base: usize,
+}
+
+impl Layout {
+    /// The layout for Sv39, which only has 256 GiB of positive addresses.
+    pub const SV39: Layout = Layout::new(
+        0x20_0000_0000,
+        0x10_0000_0000,
+        0x08_0000_0000,
+        0x18_0000_0000,
+        0x19_0000_0000,
+    );
+
+    /// The layout for Sv48.
+    pub const SV48: Layout = Layout::new(
+        0x4000_0000_0000,
+        0x1000_0000_0000,
+        0x0A00_0000_0000,
+        0x0B00_0000_0000,
+        0x0C00_0000_0000,
+    );
+
+    /// The layout for Sv57, which gives most of the additional addresses to the user half.
+    pub const SV57: Layout = Layout::new(
+        0x80_0000_0000_0000,
+        0x1000_0000_0000,
+        0x0A00_0000_0000,
+        0x0B00_0000_0000,
+        0x0C00_0000_0000,
+    );
+
+    /// Create a layout, whose kernel regions are placed at the given offsets from the start
+    /// of the higher half. The stack regions are between `stacks` and `vmem`.
+    const fn new(
+        higher_half_start: usize,
+        user_mmap_base: usize,
+        phys_mem: usize,
+        stacks: usize,
+        vmem: usize,
+    ) -> Self {
+        let step = (vmem - stacks) / 8;
+        Self {
+            kernel_stack_base: higher_half_start + stacks,
+            trap_stack_base: higher_half_start + stacks + 2 * step,
+            emergency_stack_base: higher_half_start + stacks + 3 * step,
+            thread_stack_base: higher_half_start + stacks + 4 * step,
+            vmem_alloc_base: higher_half_start + vmem,
+            higher_half_start,
+            phys_mem_base: higher_half_start + phys_mem,
+            user_mmap_base,
+        }
+    }
+
+    /// Return the layout for the given paging mode.
+    pub fn for_mode(mode: &satp::Mode) -> Self {
+        match mode {
+            satp::Mode::Sv39 => Self::SV39,
+            satp::Mode::Sv57 => Self::SV57,
+            _ => Self::SV48,
+        }
+    }
+
+    /// The end of the user half of every address space.
+    ///
+    /// Everything below this address belongs to the process, everything above is shared
+    /// with the kernel.
+    pub fn user_space_end(&self) -> usize {
+        self.higher_half_start
+    }
+
+    /// The address right above the stack of the main thread of a process.
+    pub fn user_stack_top(&self) -> usize {
+        self.user_space_end()
+    }
+
+    /// Every region that contains kernel stacks, as `(base, end, stack size)`.
+    fn stack_regions(&self) -> [(usize, usize, usize); 4] {
+        [
+            (
+                self.kernel_stack_base,
+                self.trap_stack_base,
+                KERNEL_STACK_SIZE,
+            ),
+            (
+                self.trap_stack_base,
+                self.emergency_stack_base,
+                TRAP_STACK_SIZE,
+            ),
+            (
+                self.emergency_stack_base,
+                self.thread_stack_base,
+                EMERGENCY_STACK_SIZE,
+            ),
+            (
+                self.thread_stack_base,
+                self.vmem_alloc_base,
+                THREAD_STACK_SIZE,
+            ),
+        ]
+    }
+}
 
-/// The base virtual address where the allocator will start allocating virtual memory.
-pub const KERNEL_VMEM_ALLOC_BASE: usize = HIGHER_HALF_START + 0x0C00_0000_0000;
+/// The layout that is used by the kernel, which is selected at boot.
+#[doc(hidden)]
+pub(crate) static LAYOUT: StaticCell<Layout> = StaticCell::new(Layout::SV48);
+
+/// Set the layout that is returned by [`layout`].
+///
+/// This must be called before anything is mapped into the kernel page table.
+pub unsafe fn set_layout(layout: Layout) {
+    *LAYOUT.get() = layout;
+}
+
+/// Return the virtual memory layout, that was chosen for the paging mode of the kernel.
+pub fn layout() -> &'static Layout {
+    unsafe { &*LAYOUT.get() }
+}
 
 static PHYSICAL_MEMORY_OFFSET: AtomicUsize = AtomicUsize::new(0);
 
@@ -28,8 +178,36 @@ pub unsafe fn set_phymem_offset(offset: usize) {
     PHYSICAL_MEMORY_OFFSET.store(offset, Ordering::Relaxed);
 }
 
+/// Return the offset of the physical memory window.
+pub fn phymem_offset() -> usize {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)
+}
+
 /// Convert a physical address into a virtual address using the physical memory offset.
 pub fn phys2virt(paddr: impl Into<PhysAddr>) -> VirtAddr {
     let paddr: usize = paddr.into().into();
     VirtAddr::from(paddr + PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
 }
+
+/// Convert a virtual address inside the physical memory window back into a physical address.
+pub fn virt2phys(vaddr: impl Into<VirtAddr>) -> PhysAddr {
+    let vaddr: usize = vaddr.into().into();
+    PhysAddr::from(vaddr - PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Return the lowest address of the `idx`th stack, inside the stack region at `base`.
+///
+/// Every stack in a region occupies a slot of `size` bytes plus a guard page,
+/// which is placed below the stack and never mapped.
+pub fn stack_start(base: usize, size: usize, idx: usize) -> usize {
+    base + idx * (size + STACK_GUARD_SIZE) + STACK_GUARD_SIZE
+}
+
+/// Check if the given address is inside the guard page of any kernel stack.
+pub fn is_stack_guard(addr: usize) -> bool {
+    layout()
+        .stack_regions()
+        .iter()
+        .filter(|(base, end, _)| (*base..*end).contains(&addr))
+        .any(|(base, _, size)| (addr - base) % (size + STACK_GUARD_SIZE) < STACK_GUARD_SIZE)
+}
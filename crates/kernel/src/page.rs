@@ -4,24 +4,33 @@
 mod types;
 pub use types::*;
 
+pub mod asid;
 pub mod modes;
 
 use crate::{
     allocator,
-    memmap::phys2virt,
+    memmap::{phys2virt, virt2phys},
     pmem::{self, Box, GlobalPhysicalAllocator, Vec},
+    tlb::Shootdown,
 };
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{fmt, marker::PhantomData, ops, ptr::NonNull};
-use riscv::{csr::satp, sync::MutexGuard};
+use riscv::{csr::satp, sync::IrqMutexGuard};
 
 mod sealed {
     pub trait Sealed {}
     impl Sealed for super::modes::Sv39 {}
     impl Sealed for super::modes::Sv48 {}
+    impl Sealed for super::modes::Sv57 {}
+    impl Sealed for super::modes::Dynamic {}
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
-pub type KernelPageTable = PageTable<modes::Sv48>;
+pub type KernelMode = modes::Dynamic;
+pub type KernelPageTable = PageTable<KernelMode>;
+
+/// The raw `satp` value that activates the kernel page table.
+static KERNEL_SATP: AtomicUsize = AtomicUsize::new(0);
 
 /// Errors that are related to paging.
 #[derive(Debug)]
@@ -30,6 +39,7 @@ pub enum Error {
     InvalidAddress,
     UnalignedAddress,
     AlreadyMapped,
+    InvalidFlags,
     Alloc(allocator::Error),
 }
 
@@ -37,22 +47,67 @@ pub enum Error {
 /// the paging mode to use.
 pub unsafe trait PagingMode: sealed::Sealed {
     /// The number of page table levels this paging mode supports.
-    const LEVELS: usize;
-
-    /// The largest page size this mode supports.
-    const TOP_LEVEL_SIZE: PageSize;
+    fn levels() -> usize;
 
     /// The mode that will be put into the satp CSR.
-    const SATP_MODE: satp::Mode;
+    fn satp_mode() -> satp::Mode;
+
+    /// The largest page size this mode supports.
+    fn top_level_size() -> PageSize {
+        PageSize::ALL[Self::levels() - 1]
+    }
 
     /// The maximum address that is possible to map.
-    const MAX_ADDRESS: usize;
+    fn max_address() -> usize {
+        (1 << (12 + Self::levels() * 9)) - 1
+    }
+}
+
+/// The maximum number of levels of any paging mode.
+pub const MAX_LEVELS: usize = 5;
+
+/// An intermediate table, that is owned by a [`PageTable`].
+#[derive(Debug)]
+pub struct Subtable {
+    /// The physical address of the table.
+    pub table: NonNull<[Entry; 512]>,
+    /// The level of the table, where `0` is the level that maps kilopages.
+    pub level: usize,
+    /// The number of valid entries inside the table.
+    pub used: usize,
+}
+
+/// The number of tables per level of a [`PageTable`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TableStats {
+    /// The number of tables for every level, where `0` is the level that maps kilopages.
+    pub tables: [usize; MAX_LEVELS],
+}
+
+impl TableStats {
+    /// Return the total number of tables.
+    pub fn total(&self) -> usize {
+        self.tables.iter().sum()
+    }
+}
+
+impl fmt::Display for TableStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} page tables (", self.total())?;
+        for (level, count) in self.tables.iter().enumerate().rev() {
+            let sep = if level == 0 { ")" } else { ", " };
+            write!(f, "level {}: {}{}", level, count, sep)?;
+        }
+        Ok(())
+    }
 }
 
 /// Generic representation of a page table that can support any paging mode.
 pub struct PageTable<M> {
     pub entries: Box<[Entry; 512]>,
-    pub subtables: Vec<NonNull<[Entry; 512]>>,
+    /// All intermediate tables, sorted by their address.
+    pub subtables: Vec<Subtable>,
+    asid: u16,
     _mode: PhantomData<M>,
 }
 
@@ -61,13 +116,11 @@ unsafe impl<M> Sync for PageTable<M> {}
 
 impl<M> PageTable<M> {
     /// Create a new pagetable from the raw entries pointer and a subtables pointer.
-    pub unsafe fn from_raw_parts(
-        entries: Box<[Entry; 512]>,
-        subtables: Vec<NonNull<[Entry; 512]>>,
-    ) -> Self {
+    pub unsafe fn from_raw_parts(entries: Box<[Entry; 512]>, subtables: Vec<Subtable>) -> Self {
         Self {
             entries,
             subtables,
+            asid: 0,
             _mode: PhantomData,
         }
     }
@@ -77,17 +130,13 @@ impl<M> PageTable<M> {
         Self {
             entries: Box::new_in([Entry::ZERO; 512], GlobalPhysicalAllocator),
             subtables: Vec::new_in(GlobalPhysicalAllocator),
+            asid: 0,
             _mode: PhantomData,
         }
     }
 
     /// Turn this pagetable into the raw underlying parts.
-    pub fn into_raw_parts(
-        self,
-    ) -> (
-        *mut [Entry; 512],
-        (*mut NonNull<[Entry; 512]>, usize, usize),
-    ) {
+    pub fn into_raw_parts(self) -> (*mut [Entry; 512], (*mut Subtable, usize, usize)) {
         let mut me = core::mem::ManuallyDrop::new(self);
         (
             me.entries.as_mut_ptr().cast(),
@@ -104,17 +153,82 @@ impl<M: PagingMode> PageTable<M> {
     /// Construct a value that is ready to be written into the satp CSR.
     pub fn satp(&self) -> satp::Satp {
         satp::Satp {
-            asid: 0,
-            mode: M::SATP_MODE,
-            root_table: self.entries.as_ptr() as u64,
+            asid: self.asid,
+            mode: M::satp_mode(),
+            root_table: usize::from(virt2phys(self.entries.as_ptr())) as u64,
+        }
+    }
+
+    /// Return the address space identifier of this table.
+    pub fn asid(&self) -> u16 {
+        self.asid
+    }
+
+    /// Set the address space identifier, that is used inside the [`satp`](Self::satp) value.
+    pub fn set_asid(&mut self, asid: u16) {
+        self.asid = asid;
+    }
+
+    /// Share all top level entries of `other`, that map addresses at and above `start`,
+    /// with this table.
+    ///
+    /// The subtables stay owned by `other`, so `other` must outlive this table. Top level
+    /// entries that are created inside `other` afterwards are not visible in this table,
+    /// so `other` should use [`alloc_top_level`](Self::alloc_top_level) beforehand.
+    pub fn share_from(&mut self, other: &Self, start: VirtAddr) {
+        let first = Self::vpn(start, M::levels() - 1);
+        for (entry, shared) in self.entries[first..].iter_mut().zip(&other.entries[first..]) {
+            entry.0 = shared.0;
         }
     }
 
+    /// Point every empty top level entry, that maps positive addresses at and above `start`,
+    /// to a new empty table.
+    ///
+    /// The upper half of the top level entries maps the negative, sign-extended addresses,
+    /// which are never used by the [`Layout`](crate::memmap::Layout), so they are skipped.
+    ///
+    /// Tables directly below the root table are never freed, so afterwards, every mapping
+    /// inside the range is visible in the tables that share the entries.
+    pub fn alloc_top_level(&mut self, start: VirtAddr) -> Result<()> {
+        let first = Self::vpn(start, M::levels() - 1);
+        for idx in first..256 {
+            if self.entries[idx].is_valid() {
+                continue;
+            }
+
+            let table = pmem::zalloc_order(0)
+                .map_err(Error::Alloc)?
+                .as_ptr()
+                .cast::<[Entry; 512]>();
+
+            self.entries[idx].0 = ((table as usize as u64) >> 2) | Entry::VALID;
+            self.insert_subtable(table, M::levels() - 2, 0);
+        }
+
+        Ok(())
+    }
+
+    /// Create an empty batch of TLB flushes for this table.
+    pub fn shootdown(&self) -> Shootdown {
+        Shootdown::new(self.satp().as_bits())
+    }
+
+    /// Return the number of tables per level, including the root table.
+    pub fn stats(&self) -> TableStats {
+        let mut stats = TableStats::default();
+        stats.tables[M::levels() - 1] = 1;
+        for sub in &self.subtables {
+            stats.tables[sub.level] += 1;
+        }
+        stats
+    }
+
     /// Return a debug printable version of this table.
     pub fn debug(&self) -> impl fmt::Debug + '_ {
         DebugPageTable {
             table: &*self.entries,
-            size: M::TOP_LEVEL_SIZE,
+            size: M::top_level_size(),
             addr: VirtAddr::from(0),
             _mode: PhantomData::<M>,
         }
@@ -129,12 +243,12 @@ impl<M: PagingMode> PageTable<M> {
         flags: Flags,
     ) -> Result<()> {
         // check if this paging mode supports the given pagesize
-        if size.vpn_idx() >= M::LEVELS {
+        if size.vpn_idx() >= M::levels() {
             return Err(Error::UnsupportedPageSize);
         }
 
         // validate the addresses
-        if usize::from(vaddr) >= M::MAX_ADDRESS || usize::from(vaddr) & !M::MAX_ADDRESS != 0 {
+        if usize::from(vaddr) >= M::max_address() || usize::from(vaddr) & !M::max_address() != 0 {
             return Err(Error::InvalidAddress);
         }
 
@@ -143,9 +257,11 @@ impl<M: PagingMode> PageTable<M> {
             return Err(Error::UnalignedAddress);
         }
 
-        // go through each page level in the virtual address
+        // go through each page level in the virtual address.
+        // `table_addr` is the physical address of `table`, or `None` for the root table
         let mut table = &mut *self.entries;
-        for vpn_i in (size.vpn_idx() + 1..M::LEVELS).rev() {
+        let mut table_addr = None;
+        for vpn_i in (size.vpn_idx() + 1..M::levels()).rev() {
             // get the current Vpn and the according entry
             let vpn = Self::vpn(vaddr, vpn_i);
             let entry = &mut table[vpn];
@@ -155,6 +271,7 @@ impl<M: PagingMode> PageTable<M> {
                 Some(EntryKind::Leaf) => return Err(Error::AlreadyMapped),
                 // this entry points to the next table, so traverse the next level
                 Some(EntryKind::Branch(next)) => {
+                    table_addr = Some(next);
                     let next = phys2virt(next);
                     table = unsafe { next.as_ptr::<[Entry; 512]>().as_mut().unwrap() };
                 }
@@ -169,8 +286,9 @@ impl<M: PagingMode> PageTable<M> {
 
                     // update the current entry to point to the new page
                     entry.0 = ((table_ptr as usize as u64) >> 2) | Entry::VALID;
-                    self.subtables
-                        .push(unsafe { NonNull::new_unchecked(table_ptr) });
+                    Self::add_used(&mut self.subtables, table_addr, 1);
+                    self.insert_subtable(table_ptr, vpn_i - 1, 0);
+                    table_addr = Some(PhysAddr::from(table_ptr));
 
                     // traverse the newly allocated table
                     table = unsafe {
@@ -199,6 +317,7 @@ impl<M: PagingMode> PageTable<M> {
         let ppn = usize::from(paddr) as u64 >> 12;
         let new_entry = (ppn << 10) | flags.bits() as u64 | Entry::VALID;
         entry.0 = new_entry as u64;
+        Self::add_used(&mut self.subtables, table_addr, 1);
 
         // flush tlb for this page
         riscv::asm::sfence(usize::from(vaddr), None);
@@ -207,6 +326,9 @@ impl<M: PagingMode> PageTable<M> {
     }
 
     /// Create a new virtual mapping at `vaddr` for `count` pages of the given page size.
+    ///
+    /// If a page can't be allocated or mapped, the pages that were already mapped
+    /// are unmapped and freed again.
     pub fn map_alloc(
         &mut self,
         vaddr: VirtAddr,
@@ -218,24 +340,93 @@ impl<M: PagingMode> PageTable<M> {
         let size = page_size.size() * count;
         let end = usize::from(vaddr) + size;
 
-        // the order that will be used for the buddy allocator for ech page size
-        let order = match page_size {
-            PageSize::Kilopage => 0,
-            PageSize::Megapage => 9,
-            _ => unimplemented!(),
-        };
+        // check if this paging mode supports the given pagesize,
+        // before allocating any memory
+        if page_size.vpn_idx() >= M::levels() {
+            return Err(Error::UnsupportedPageSize);
+        }
+
+        // loop through the whole mapping and map every required page.
+        // buddy blocks are aligned to their size, so the page is aligned too
+        let start = usize::from(vaddr);
+        for vaddr in (start..end).step_by(page_size.size()) {
+            // alloc the page, and map it
+            let res = pmem::alloc_order(page_size.order())
+                .map_err(Error::Alloc)
+                .and_then(|page| {
+                    let paddr = PhysAddr::from(page.as_ptr());
+                    self.map(paddr, vaddr.into(), page_size, flags)
+                        .map_err(|err| {
+                            let _ = unsafe { pmem::free_order(page, page_size.order()) };
+                            err
+                        })
+                });
+
+            if let Err(err) = res {
+                // unmap and free every page that was already mapped by this call
+                let mut shootdown = self.shootdown();
+                for page in (start..vaddr).step_by(page_size.size()) {
+                    let (paddr, _, _) = self.translate(page.into()).unwrap();
+                    let _ = self.unmap_with(page.into(), &mut shootdown);
+                    shootdown.free_after(NonNull::new(paddr.as_ptr()).unwrap(), page_size.order());
+                }
+
+                shootdown.flush();
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Map the physical range of `size` bytes starting at `paddr` to `vaddr`.
+    ///
+    /// Every chunk of the range is mapped using the largest page size, that both addresses
+    /// are aligned to, and that fits into the rest of the range. If a chunk can't be mapped,
+    /// the chunks that were already mapped are unmapped again.
+    pub fn map_range(
+        &mut self,
+        paddr: PhysAddr,
+        vaddr: VirtAddr,
+        size: usize,
+        flags: Flags,
+    ) -> Result<()> {
+        if !PageSize::Kilopage.is_aligned(size) {
+            return Err(Error::UnalignedAddress);
+        }
 
-        // loop through the whole mapping and map every required page
-        for vaddr in (vaddr.into()..end).step_by(page_size.size()) {
-            // alloc the page
-            let page = pmem::alloc_order(order).map_err(Error::Alloc)?;
-            let paddr = PhysAddr::from(page.as_ptr());
+        let (paddr, vaddr) = (usize::from(paddr), usize::from(vaddr));
+        let mut off = 0;
+        while off < size {
+            let page_size = PageSize::ALL
+                .iter()
+                .rev()
+                .copied()
+                .filter(|page_size| page_size.vpn_idx() < M::levels())
+                .find(|page_size| {
+                    page_size.is_aligned(paddr + off)
+                        && page_size.is_aligned(vaddr + off)
+                        && page_size.size() <= size - off
+                })
+                .ok_or(Error::UnalignedAddress)?;
+
+            let res = self.map((paddr + off).into(), (vaddr + off).into(), page_size, flags);
+            if let Err(err) = res {
+                // remove everything that was mapped by this call, using the page sizes
+                // that were chosen above
+                let mut shootdown = self.shootdown();
+                let mut undo = 0;
+                while undo < off {
+                    let (_, size, _) = self.translate((vaddr + undo).into()).unwrap();
+                    let _ = self.unmap_with((vaddr + undo).into(), &mut shootdown);
+                    undo += size.size();
+                }
 
-            // map the new page
-            self.map(paddr, vaddr.into(), page_size, flags)?;
+                shootdown.flush();
+                return Err(err);
+            }
 
-            // flush tlb for this page
-            riscv::asm::sfence(vaddr, None);
+            off += page_size.size();
         }
 
         Ok(())
@@ -249,29 +440,112 @@ impl<M: PagingMode> PageTable<M> {
         let (_, page_size, _) = self.translate(vaddr).ok_or(Error::InvalidAddress)?;
         let end = usize::from(vaddr) + (page_size.size() * count);
 
-        // the order that will be used for the buddy allocator for freeing the pages
-        let order = match page_size {
-            PageSize::Kilopage => 0,
-            PageSize::Megapage => 9,
-            _ => unimplemented!(),
-        };
-
-        // loop through the rest of the pages and deallocate them too
+        // loop through the rest of the pages and deallocate them too. the pages are only
+        // freed after all of them were flushed from every tlb
+        let mut shootdown = self.shootdown();
         for page in (usize::from(vaddr)..end).step_by(page_size.size()) {
             // translate the address to find the physaddr which we need for deallocation
             let (paddr, _, _) = self.translate(page.into()).unwrap();
 
-            // unmap the page
-            assert!(self.unmap(page.into())?);
+            assert!(self.unmap_with(page.into(), &mut shootdown)?);
+            shootdown.free_after(NonNull::new(paddr.as_ptr()).unwrap(), page_size.order());
+        }
+
+        shootdown.flush();
+        Ok(())
+    }
 
-            // flush tlb for this page
-            riscv::asm::sfence(usize::from(vaddr), None);
+    /// Replace the flags of every page that is mapped inside `range`.
+    ///
+    /// Huge pages that are only partially inside the range are split into smaller pages first.
+    /// Addresses inside the range that are not mapped are skipped. The changed pages are
+    /// flushed from the TLB of every hart that uses this table.
+    pub fn protect(&mut self, range: ops::Range<VirtAddr>, flags: Flags) -> Result<()> {
+        let (start, end) = (usize::from(range.start), usize::from(range.end));
+        if !PageSize::Kilopage.is_aligned(start) || !PageSize::Kilopage.is_aligned(end) {
+            return Err(Error::UnalignedAddress);
+        }
 
-            // deallocate the page
-            pmem::free_order(NonNull::new(paddr.as_ptr()).unwrap(), order)
-                .map_err(Error::Alloc)?;
+        // without any permission the entry would become a branch, and `W` requires `R`
+        if !flags.intersects(Flags::READ | Flags::EXEC)
+            || flags & (Flags::READ | Flags::WRITE) == Flags::WRITE
+        {
+            return Err(Error::InvalidFlags);
         }
 
+        let mut shootdown = self.shootdown();
+        let mut addr = start;
+        while addr < end {
+            // find the entry that maps `addr`, and the level it's on
+            let mut table = self.entries.as_mut_ptr().cast::<[Entry; 512]>();
+            let mut level = M::levels() - 1;
+            let entry = loop {
+                let entry = unsafe { &mut (*table)[Self::vpn(addr.into(), level)] };
+                match entry.kind() {
+                    Some(EntryKind::Leaf) => break Some(entry),
+                    Some(EntryKind::Branch(next)) if level > 0 => {
+                        table = phys2virt(next).as_ptr();
+                        level -= 1;
+                    }
+                    _ => break None,
+                }
+            };
+
+            let size = PageSize::ALL[level].size();
+            let page = addr & !(size - 1);
+
+            match entry {
+                // the page is completely inside the range, so update it in place
+                Some(entry) if page == addr && page + size <= end => {
+                    let bits = (entry.0 & !0xFF) | flags.bits() as u64 | Entry::VALID;
+                    unsafe { core::ptr::write_volatile(entry, Entry(bits)) };
+                    shootdown.add(page, size);
+                }
+                // only a part of the page is affected, so split it and try again
+                Some(entry) => match self.split(entry, level) {
+                    Ok(()) => continue,
+                    Err(err) => {
+                        shootdown.flush();
+                        return Err(err);
+                    }
+                },
+                None => {}
+            }
+
+            addr = page + size;
+        }
+
+        shootdown.flush();
+        Ok(())
+    }
+
+    /// Turn the huge page `entry`, which is at `level`, into a branch to a new table
+    /// that maps the same memory using pages of the next smaller size.
+    fn split(&mut self, entry: &mut Entry, level: usize) -> Result<()> {
+        let table_ptr = pmem::zalloc_order(0)
+            .map_err(Error::Alloc)?
+            .as_ptr()
+            .cast::<[Entry; 512]>();
+        let table = unsafe {
+            phys2virt(table_ptr)
+                .as_ptr::<[Entry; 512]>()
+                .as_mut()
+                .unwrap()
+        };
+
+        // every new entry keeps the flags of the huge page
+        let ppn = usize::from(entry.addr()) as u64 >> 12;
+        let step = PageSize::ALL[level - 1].size() as u64 >> 12;
+        for (idx, new) in table.iter_mut().enumerate() {
+            new.0 = ((ppn + idx as u64 * step) << 10) | (entry.0 & 0x3FF);
+        }
+
+        self.insert_subtable(table_ptr, level - 1, 512);
+
+        // the old translation stays the same, so there's no need to flush it here
+        let bits = ((table_ptr as usize as u64) >> 2) | Entry::VALID;
+        unsafe { core::ptr::write_volatile(entry, Entry(bits)) };
+
         Ok(())
     }
 
@@ -289,6 +563,7 @@ impl<M: PagingMode> PageTable<M> {
                 PageSize::Megapage => off & 0x1F_FFFF,
                 PageSize::Gigapage => off & 0x3FFF_FFFF,
                 PageSize::Terapage => off & 0x7F_FFFF_FFFF,
+                PageSize::Petapage => off & 0xFFFF_FFFF_FFFF,
             };
 
             // get the physical page number specified by the PTE
@@ -298,29 +573,122 @@ impl<M: PagingMode> PageTable<M> {
         })
     }
 
-    /// Try to unmap the given virtual address.
+    /// Try to unmap the given virtual address, and flush it from the TLB of every hart
+    /// that uses this table.
     ///
     /// The bool indicates if there was a virtual address that was unmapped.
     /// It's `false` if `vaddr` is not mapped.
+    ///
+    /// Intermediate tables that become empty are freed, except the ones directly below
+    /// the root table, because their root entries may be shared with other tables
+    /// using [`share_from`](Self::share_from).
     pub fn unmap(&mut self, vaddr: VirtAddr) -> Result<bool> {
-        let Mapping { entry, .. } = match self.traverse(vaddr) {
-            Some(x) => x,
-            // there's no mapping the for given address
-            None => return Ok(false),
-        };
+        let mut shootdown = self.shootdown();
+        let unmapped = self.unmap_with(vaddr, &mut shootdown)?;
+        shootdown.flush();
+        Ok(unmapped)
+    }
+
+    /// Unmap the given virtual address like [`unmap`](Self::unmap), but only add it to
+    /// `shootdown`, so many pages can be flushed at once.
+    ///
+    /// Intermediate tables that become empty are freed after the flush.
+    pub fn unmap_with(&mut self, vaddr: VirtAddr, shootdown: &mut Shootdown) -> Result<bool> {
+        // walk down to the leaf, and remember every entry on the way
+        // together with the physical address of the table it's inside
+        let mut path = [(None, core::ptr::null_mut::<Entry>()); MAX_LEVELS];
+        let mut depth = 0;
+        let mut table = self.entries.as_mut_ptr().cast::<[Entry; 512]>();
+        let mut table_addr = None;
+
+        for level in (0..M::levels()).rev() {
+            let entry = unsafe { &mut (*table)[Self::vpn(vaddr, level)] };
+            path[depth] = (table_addr, entry as *mut Entry);
+            depth += 1;
+
+            match entry.kind() {
+                Some(EntryKind::Leaf) => break,
+                Some(EntryKind::Branch(next)) if level > 0 => {
+                    table_addr = Some(next);
+                    table = phys2virt(next).as_ptr();
+                }
+                // there's no mapping the for given address
+                _ => return Ok(false),
+            }
+        }
+
+        // clear the leaf, and every entry that points to a table which became empty
+        let mut freed = [None; MAX_LEVELS];
+        for (idx, &(table_addr, entry)) in path[..depth].iter().enumerate().rev() {
+            unsafe { core::ptr::write_volatile(entry, Entry::ZERO) };
 
-        // clear the entry by zeroing it
-        unsafe {
-            core::ptr::write_volatile(entry, Entry::ZERO);
+            let sub = match Self::add_used(&mut self.subtables, table_addr, -1) {
+                Some(sub) => sub,
+                None => break,
+            };
+
+            let sub = &self.subtables[sub];
+            if sub.used != 0 || sub.level == M::levels() - 2 {
+                break;
+            }
+
+            freed[idx] = Some(sub.table);
+        }
+
+        // the hardware may have cached the entries that point to the freed tables too,
+        // so they must be flushed before the tables are reused. flushing a single address
+        // only removes the cached leaf entries
+        let level = M::levels() - depth;
+        let page = usize::from(vaddr) & !(PageSize::ALL[level].size() - 1);
+        if freed.iter().any(Option::is_some) {
+            shootdown.add_all();
+        } else {
+            shootdown.add(page, PageSize::ALL[level].size());
         }
+
+        for table in freed.iter().flatten() {
+            let idx = self
+                .subtables
+                .binary_search_by_key(table, |sub| sub.table)
+                .unwrap();
+            self.subtables.remove(idx);
+
+            shootdown.free_after(table.cast(), 0);
+        }
+
         Ok(true)
     }
 
+    /// Take ownership of the table at the physical address `table`.
+    fn insert_subtable(&mut self, table: *mut [Entry; 512], level: usize, used: usize) {
+        let table = NonNull::new(table).unwrap();
+        let idx = self.subtables.partition_point(|sub| sub.table < table);
+        self.subtables.insert(idx, Subtable { table, level, used });
+    }
+
+    /// Add `delta` to the number of used entries of the subtable at `table`, and return
+    /// its index inside `subtables`. Returns `None` for the root table, or a table
+    /// that isn't owned by this page table.
+    fn add_used(
+        subtables: &mut [Subtable],
+        table: Option<PhysAddr>,
+        delta: isize,
+    ) -> Option<usize> {
+        let table = NonNull::new(table?.as_ptr::<[Entry; 512]>())?;
+        let idx = subtables
+            .binary_search_by_key(&table, |sub| sub.table)
+            .ok()?;
+
+        let sub = &mut subtables[idx];
+        sub.used = (sub.used as isize + delta) as usize;
+        Some(idx)
+    }
+
     /// Traverse the page table and search for the given virtual address.
     fn traverse(&self, vaddr: VirtAddr) -> Option<Mapping> {
         // represent the current table that is walked.
         let mut table = &*self.entries;
-        let mut idx = M::LEVELS - 1;
+        let mut idx = M::levels() - 1;
 
         // we store the level 1 and 2 tables to return them
         let mut table_mib = None;
@@ -364,6 +732,8 @@ impl<M: PagingMode> PageTable<M> {
                 0 => PageSize::Kilopage,
                 1 => PageSize::Megapage,
                 2 => PageSize::Gigapage,
+                3 => PageSize::Terapage,
+                4 => PageSize::Petapage,
                 _ => unreachable!(),
             },
         })
@@ -389,8 +759,8 @@ fn set_vpn(vaddr: VirtAddr, idx: usize, val: usize) -> VirtAddr {
 
 impl<M> Drop for PageTable<M> {
     fn drop(&mut self) {
-        for ptr in self.subtables.drain(..) {
-            let _ = unsafe { pmem::free(ptr.cast()) };
+        for sub in self.subtables.drain(..) {
+            let _ = unsafe { pmem::free(sub.table.cast()) };
         }
     }
 }
@@ -412,21 +782,43 @@ impl Entry {
     const VALID: u64 = 1 << 0;
     const ZERO: Entry = Entry(0);
 
+    /// Return the raw bits of this entry.
+    #[inline]
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Check if the `V` bit of this entry is set.
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.0 & Entry::VALID != 0
+    }
+
+    /// Check if this entry maps a page, instead of pointing to the next level.
+    #[inline]
+    pub fn is_leaf(&self) -> bool {
+        matches!(self.kind(), Some(EntryKind::Leaf))
+    }
+
+    /// Return the physical address this entry points to, which is either
+    /// a page or the table of the next level.
+    #[inline]
+    pub fn addr(&self) -> PhysAddr {
+        PhysAddr::from(((self.0 as usize >> 10) & 0x0FFF_FFFF_FFFF) << 12)
+    }
+
     fn kind(&self) -> Option<EntryKind> {
         let valid = self.0 & Entry::VALID != 0;
         match (valid, valid && self.flags() == Flags::empty()) {
-            (true, true) => {
-                let next = ((self.0 as usize >> 10) & 0x0FFF_FFFF_FFFF) << 12;
-                let next = PhysAddr::from(next);
-                Some(EntryKind::Branch(next))
-            }
+            (true, true) => Some(EntryKind::Branch(self.addr())),
             (true, false) => Some(EntryKind::Leaf),
             _ => None,
         }
     }
 
+    /// Return the permission and status bits of this entry.
     #[inline]
-    fn flags(&self) -> Flags {
+    pub fn flags(&self) -> Flags {
         // set the V bit to 0 because it's not part of the flags
         let flags = self.0 as u8 >> 1 << 1;
         Flags::from_bits_truncate(flags)
@@ -439,6 +831,67 @@ enum EntryKind {
     Leaf,
 }
 
+/// A single level of a page table walk, see [`walk`].
+#[derive(Debug)]
+pub struct WalkStep {
+    /// The size of the page that is mapped by a leaf at this level.
+    pub size: PageSize,
+    /// The index of the entry inside the table of this level.
+    pub index: usize,
+    /// A copy of the entry.
+    pub entry: Entry,
+}
+
+/// Iterator over every entry that is used to translate an address, see [`walk`].
+#[derive(Debug)]
+pub struct Walk {
+    table: Option<PhysAddr>,
+    size: PageSize,
+    vaddr: VirtAddr,
+}
+
+impl Iterator for Walk {
+    type Item = WalkStep;
+
+    fn next(&mut self) -> Option<WalkStep> {
+        let table = phys2virt(self.table?.as_ptr::<u8>()).as_ptr::<Entry>();
+        let index = (usize::from(self.vaddr) >> (12 + self.size.vpn_idx() * 9)) & 0x1FF;
+        let entry = Entry(unsafe { core::ptr::read_volatile(table.add(index) as *const u64) });
+        let size = self.size;
+
+        // continue only if this entry points to the next level
+        self.table = match (entry.kind(), size.step()) {
+            (Some(EntryKind::Branch(next)), Some(step)) => {
+                self.size = step;
+                Some(next)
+            }
+            _ => None,
+        };
+
+        Some(WalkStep { size, index, entry })
+    }
+}
+
+/// Walk the page table that is described by the given `satp` value, and return
+/// the entry of every level that is used to translate `vaddr`.
+///
+/// This doesn't take any lock, so it can be used to inspect a page table while
+/// its owner is interrupted, for example when reporting a fatal trap.
+pub unsafe fn walk(satp: &satp::Satp, vaddr: VirtAddr) -> Walk {
+    let (table, size) = match satp.mode {
+        satp::Mode::Bare => (None, PageSize::Kilopage),
+        satp::Mode::Sv39 => (Some(satp.root_table), PageSize::Gigapage),
+        satp::Mode::Sv48 => (Some(satp.root_table), PageSize::Terapage),
+        satp::Mode::Sv57 => (Some(satp.root_table), PageSize::Petapage),
+    };
+
+    Walk {
+        table: table.map(|addr| PhysAddr::from(addr as usize)),
+        size,
+        vaddr,
+    }
+}
+
 struct DebugPageTable<'page, M: PagingMode> {
     table: &'page [Entry; 512],
     size: PageSize,
@@ -462,6 +915,7 @@ impl<M: PagingMode> fmt::Debug for DebugPageTable<'_, M> {
                             PageSize::Megapage => 'M',
                             PageSize::Gigapage => 'G',
                             PageSize::Terapage => 'T',
+                            PageSize::Petapage => 'P',
                         },
                         set_vpn(self.addr, self.size.vpn_idx(), idx),
                         (entry.0 >> 10 << 12) as usize as *const u8,
@@ -489,7 +943,18 @@ impl<M: PagingMode> fmt::Debug for DebugPageTable<'_, M> {
     }
 }
 
+/// Return the raw `satp` value that activates the kernel page table.
+pub fn kernel_satp() -> usize {
+    KERNEL_SATP.load(Ordering::Relaxed)
+}
+
+/// Set the raw `satp` value that is returned by [`kernel_satp`].
+pub unsafe fn set_kernel_satp(satp: usize) {
+    KERNEL_SATP.store(satp, Ordering::Relaxed);
+}
+
 /// Get exclusive access to the global page table, if there is one.
+#[track_caller]
 pub fn root() -> TableGuard {
     TableGuard {
         guard: crate::boot::PAGE_TABLE.lock(),
@@ -498,7 +963,7 @@ pub fn root() -> TableGuard {
 
 /// Structure that protects access to the global page table.
 pub struct TableGuard {
-    guard: MutexGuard<'static, Option<KernelPageTable>>,
+    guard: IrqMutexGuard<'static, Option<KernelPageTable>>,
 }
 
 impl ops::Deref for TableGuard {
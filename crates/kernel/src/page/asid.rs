@@ -0,0 +1,55 @@
+//! Allocation of address space identifiers.
+//!
+//! The ASID `0` is used by the kernel page table, and by every address space
+//! if the hardware doesn't support ASIDs.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+use riscv::{csr::satp, sync::Mutex};
+
+/// The largest ASID that is supported by the hardware.
+static MAX_ASID: AtomicU16 = AtomicU16::new(0);
+
+/// Bitmap of every ASID that is in use.
+static USED: Mutex<[u64; 1024]> = Mutex::new([0; 1024]);
+
+/// Return the lock statistics of the ASID bitmap.
+#[cfg(feature = "lock-stats")]
+pub(crate) fn lock_stats() -> &'static riscv::sync::stats::LockStats {
+    USED.stats()
+}
+
+/// Find out how many ASID bits are supported, by writing all ones into the
+/// ASID field of `satp` and reading back which of them stuck.
+pub unsafe fn init() {
+    let old = satp::read();
+    satp::write(satp::Satp {
+        asid: u16::MAX,
+        ..old.clone()
+    });
+    let max = satp::read().asid;
+    satp::write(old);
+
+    MAX_ASID.store(max, Ordering::Relaxed);
+}
+
+/// Return the largest ASID that is supported by the hardware.
+pub fn max() -> u16 {
+    MAX_ASID.load(Ordering::Relaxed)
+}
+
+/// Allocate an unused ASID. Returns `None` if every ASID is in use.
+pub fn alloc() -> Option<u16> {
+    let mut used = USED.lock();
+    let asid = (1..=max() as usize).find(|&asid| used[asid / 64] & (1 << (asid % 64)) == 0)?;
+
+    used[asid / 64] |= 1 << (asid % 64);
+    Some(asid as u16)
+}
+
+/// Give the ASID back, and flush every TLB entry that is tagged with it.
+pub fn free(asid: u16) {
+    crate::tlb::flush_asid(asid);
+
+    let asid = asid as usize;
+    USED.lock()[asid / 64] &= !(1 << (asid % 64));
+}
@@ -1,22 +1,147 @@
-use super::{PageSize, PagingMode};
+use super::{Entry, Flags, PageSize, PagingMode};
+use crate::pmem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use devicetree::DeviceTree;
 use riscv::csr::satp;
 
 /// The Sv39 paging mode which supports 39-bit virtual addresses.
 pub enum Sv39 {}
 
 unsafe impl PagingMode for Sv39 {
-    const LEVELS: usize = 3;
-    const TOP_LEVEL_SIZE: PageSize = PageSize::Gigapage;
-    const SATP_MODE: satp::Mode = satp::Mode::Sv39;
-    const MAX_ADDRESS: usize = 0x7F_FFFF_FFFF;
+    fn levels() -> usize {
+        3
+    }
+
+    fn satp_mode() -> satp::Mode {
+        satp::Mode::Sv39
+    }
 }
 
 /// The Sv48 paging mode which supports 48-bit virtual addresses.
 pub enum Sv48 {}
 
 unsafe impl PagingMode for Sv48 {
-    const LEVELS: usize = 4;
-    const TOP_LEVEL_SIZE: PageSize = PageSize::Terapage;
-    const SATP_MODE: satp::Mode = satp::Mode::Sv48;
-    const MAX_ADDRESS: usize = 0xFFFF_FFFF_FFFF;
+    fn levels() -> usize {
+        4
+    }
+
+    fn satp_mode() -> satp::Mode {
+        satp::Mode::Sv48
+    }
+}
+
+/// The Sv57 paging mode which supports 57-bit virtual addresses.
+pub enum Sv57 {}
+
+unsafe impl PagingMode for Sv57 {
+    fn levels() -> usize {
+        5
+    }
+
+    fn satp_mode() -> satp::Mode {
+        satp::Mode::Sv57
+    }
+}
+
+/// The paging mode that was chosen at boot using [`select`].
+pub enum Dynamic {}
+
+/// The number of levels of the [`Dynamic`] paging mode.
+static LEVELS: AtomicUsize = AtomicUsize::new(4);
+
+unsafe impl PagingMode for Dynamic {
+    fn levels() -> usize {
+        LEVELS.load(Ordering::Relaxed)
+    }
+
+    fn satp_mode() -> satp::Mode {
+        match Self::levels() {
+            3 => satp::Mode::Sv39,
+            4 => satp::Mode::Sv48,
+            _ => satp::Mode::Sv57,
+        }
+    }
+}
+
+/// Set the paging mode that is used by [`Dynamic`].
+///
+/// This must be called before any page table of the [`Dynamic`] mode is created.
+pub unsafe fn select(mode: satp::Mode) {
+    assert!(
+        !matches!(mode, satp::Mode::Bare),
+        "paging can't be disabled"
+    );
+    LEVELS.store(levels(&mode), Ordering::Relaxed);
+}
+
+/// Return the number of levels of the given mode.
+fn levels(mode: &satp::Mode) -> usize {
+    match mode {
+        satp::Mode::Bare => 0,
+        satp::Mode::Sv39 => 3,
+        satp::Mode::Sv48 => 4,
+        satp::Mode::Sv57 => 5,
+    }
+}
+
+/// Find the largest paging mode, that is supported by every hart.
+///
+/// The `mmu-type` property of every cpu inside the devicetree limits the mode, and
+/// the mode is verified by writing it into `satp` on the current hart.
+///
+/// Must be called while paging is disabled.
+pub unsafe fn probe(fdt: &DeviceTree<'_>) -> Option<satp::Mode> {
+    let limit = fdt
+        .find_nodes("/cpus/cpu@")
+        .filter_map(|cpu| match cpu.prop("mmu-type")?.as_str()? {
+            "riscv,sv39" => Some(3),
+            "riscv,sv48" => Some(4),
+            "riscv,sv57" => Some(5),
+            _ => None,
+        })
+        .min()
+        .unwrap_or(5);
+
+    [satp::Mode::Sv57, satp::Mode::Sv48, satp::Mode::Sv39]
+        .iter()
+        .filter(|mode| levels(mode) <= limit)
+        .find(|mode| is_supported(mode))
+        .cloned()
+}
+
+/// Check if the current hart supports the given mode.
+///
+/// Writing an unsupported mode into `satp` has no effect, so the mode is enabled for a
+/// moment, using a table that maps the code which does it to itself.
+unsafe fn is_supported(mode: &satp::Mode) -> bool {
+    let table = match pmem::zalloc() {
+        Ok(table) => table.cast::<[Entry; 512]>(),
+        Err(_) => return false,
+    };
+
+    // map the page of the largest size, that contains this function, to itself
+    let top = PageSize::ALL[levels(mode) - 1];
+    let addr = is_supported as usize & !(top.size() - 1);
+    let idx = (addr >> (12 + top.vpn_idx() * 9)) & 0x1FF;
+    let flags = Flags::READ | Flags::EXEC | Flags::ACCESSED | Flags::DIRTY;
+    (*table.as_ptr())[idx] =
+        Entry(((addr as u64 >> 12) << 10) | flags.bits() as u64 | Entry::VALID);
+
+    let satp = satp::Satp {
+        mode: mode.clone(),
+        asid: 0,
+        root_table: table.as_ptr() as u64,
+    };
+
+    let read: usize;
+    asm!("
+        csrw satp, {0}
+        sfence.vma
+        csrr {0}, satp
+        csrw satp, zero
+        sfence.vma
+    ", inout(reg) satp.as_bits() => read);
+
+    let _ = pmem::free(table.cast());
+    read >> 60 == satp.as_bits() >> 60
 }
@@ -69,9 +69,19 @@ pub enum PageSize {
     Megapage,
     Gigapage,
     Terapage,
+    Petapage,
 }
 
 impl PageSize {
+    /// All page sizes, from the smallest to the largest one.
+    pub const ALL: [PageSize; 5] = [
+        PageSize::Kilopage,
+        PageSize::Megapage,
+        PageSize::Gigapage,
+        PageSize::Terapage,
+        PageSize::Petapage,
+    ];
+
     /// Check if a given address is aligned to the boundary of this page size.
     pub fn is_aligned(self, addr: usize) -> bool {
         let align = self.size();
@@ -88,9 +98,15 @@ impl PageSize {
             PageSize::Megapage => 2 * unit::MIB,
             PageSize::Gigapage => 1 * unit::GIB,
             PageSize::Terapage => 512 * unit::GIB,
+            PageSize::Petapage => 256 * unit::TIB,
         }
     }
 
+    /// Return the buddy allocator order, that allocates exactly one page of this size.
+    pub fn order(self) -> usize {
+        self.vpn_idx() * 9
+    }
+
     /// Return the index of the VPN that specifies this page size.
     pub fn vpn_idx(self) -> usize {
         match self {
@@ -98,6 +114,7 @@ impl PageSize {
             PageSize::Megapage => 1,
             PageSize::Gigapage => 2,
             PageSize::Terapage => 3,
+            PageSize::Petapage => 4,
         }
     }
 
@@ -108,6 +125,7 @@ impl PageSize {
             PageSize::Megapage => Some(PageSize::Kilopage),
             PageSize::Gigapage => Some(PageSize::Megapage),
             PageSize::Terapage => Some(PageSize::Gigapage),
+            PageSize::Petapage => Some(PageSize::Terapage),
         }
     }
 }
@@ -1,8 +1,20 @@
-use crate::hart;
+use crate::{
+    backtrace::{self, Backtrace},
+    hart, symbols,
+};
 use core::fmt::{self, Write};
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-struct PanicPrinter<'panic>(&'panic PanicInfo<'panic>);
+/// Set by the boot hart before it stops all other harts.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Check if the boot hart paniced and is stopping all other harts.
+pub(crate) fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Acquire)
+}
+
+struct PanicPrinter<'panic>(&'panic PanicInfo<'panic>, Backtrace);
 
 impl fmt::Display for PanicPrinter<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -25,12 +37,31 @@ impl fmt::Display for PanicPrinter<'_> {
                 write!(f, "line {}, file {}", loc.line(), loc.file())
             }
             (None, None) => write!(f, "no information available."),
+        }?;
+
+        write!(f, "\n\nbacktrace:\n{}", backtrace::Display(self.1.clone()))?;
+
+        // if the panic happened inside a trap handler, the code that was interrupted
+        // is more interesting than the trap handler itself
+        if let Some((pc, fp)) = hart::try_current().and_then(|ctx| ctx.trap_origin()) {
+            write!(
+                f,
+                "\ninterrupted at {:#x} {}:\n{}",
+                backtrace::unrelocate(pc),
+                symbols::Display(pc),
+                backtrace::Display(Backtrace::from_fp(fp))
+            )?;
         }
+
+        Ok(())
     }
 }
 
 #[panic_handler]
 fn panic_handler(info: &PanicInfo<'_>) -> ! {
+    // the backtrace has to start here, because the formatting code doesn't use frame pointers
+    let backtrace = Backtrace::here();
+
     if hart::try_current().map_or(true, |c| c.is_bsp()) {
         // if a panic on the main hart occurrs, don't use the global logger because it might
         // deadlock, instead use directly print to the SBI output.
@@ -44,17 +75,19 @@ fn panic_handler(info: &PanicInfo<'_>) -> ! {
 
         // before printing the panic, shutdown all other harts
         if let Some(hart) = hart::try_current() {
+            PANICKING.store(true, Ordering::Release);
+
             let mut mask = sbi::HartMask::all_from_base(0);
             mask.mask &= !(1 << hart.id());
 
             let _ = sbi::ipi::send_ipi(mask);
         }
 
-        let _ = write!(&mut PanicLogger, "{}", PanicPrinter(info));
+        let _ = write!(&mut PanicLogger, "{}", PanicPrinter(info, backtrace));
 
         sbi::system::fail_shutdown();
     } else {
-        log::error!("{}", PanicPrinter(info));
+        log::error!("{}", PanicPrinter(info, backtrace));
 
         // try to stop this hart if it paniced
         let _ = sbi::hsm::stop();
@@ -10,10 +10,10 @@ use crate::{
         rangeset::{self, Range},
         BuddyAllocator, RangeSet,
     },
-    memmap, page,
+    memmap,
 };
 use devicetree::DeviceTree;
-use riscv::sync::Mutex;
+use riscv::sync::IrqMutex;
 
 /// A `Box` that will use the global physical memory allocator to allocate memory.
 pub type Box<T> = alloc::boxed::Box<T, GlobalPhysicalAllocator>;
@@ -82,10 +82,16 @@ fn get_blocked_ranges() -> [Range; 2] {
     ]
 }
 
-static PHYS_MEM: PhysicalAllocator = PhysicalAllocator(Mutex::new(BuddyAllocator::new()));
+static PHYS_MEM: PhysicalAllocator = PhysicalAllocator(IrqMutex::new(BuddyAllocator::new()));
+
+/// Return the lock statistics of the physical memory allocator.
+#[cfg(feature = "lock-stats")]
+pub(crate) fn lock_stats() -> &'static riscv::sync::stats::LockStats {
+    PHYS_MEM.0.stats()
+}
 
 /// The global allocator that is responsible for allocating phyical memory.
-pub struct PhysicalAllocator(Mutex<BuddyAllocator>);
+pub struct PhysicalAllocator(IrqMutex<BuddyAllocator>);
 
 unsafe impl Send for PhysicalAllocator {}
 unsafe impl Sync for PhysicalAllocator {}
@@ -128,10 +134,15 @@ unsafe impl Allocator for PhysicalAllocator {
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
         // get the order for the requested size
         let order = allocator::order_for_size(layout.size());
-        let ptr = page::root()
-            .translate(ptr.as_ptr().into())
-            .map(|(a, _, _)| NonNull::new(a.as_ptr()).unwrap())
-            .unwrap_or(ptr);
+
+        // allocations are handed out inside the physical memory window, except the ones that
+        // were made before paging was enabled. this must not take the page table lock,
+        // since page tables themselves are freed while it's held
+        let ptr = if ptr.as_ptr() as usize >= memmap::phymem_offset() {
+            NonNull::new(memmap::virt2phys(ptr.as_ptr()).as_ptr()).unwrap()
+        } else {
+            ptr
+        };
 
         // perform the deallocation
         match self.0.lock().deallocate(ptr, order) {
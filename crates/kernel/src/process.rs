@@ -0,0 +1,308 @@
+//! User mode processes.
+//!
+//! Every process has its own page table that maps the user half of the address space,
+//! which is everything below [`user_space_end`](crate::memmap::Layout::user_space_end).
+//! The kernel half is shared with the [global page table](page::root), so the kernel stays
+//! mapped while a process is active.
+//!
+//! The user half is described by [virtual memory areas](vma), and its pages are allocated
+//! by the page fault handler, when they are accessed for the first time.
+
+pub mod mm;
+pub mod vma;
+
+pub use mm::{Access, AddressSpace, Fault};
+pub use vma::{Vma, VmaKind};
+
+use crate::{
+    allocator::PAGE_SIZE,
+    elf::{self, Elf},
+    memmap::{self, phys2virt, USER_PIE_BASE},
+    page::{self, Flags, KernelMode, PageTable, VirtAddr},
+    thread::{self, JoinHandle},
+    trap::{SSTATUS_SIE, SSTATUS_SPIE, SSTATUS_SPP},
+};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::{cmp, slice};
+use riscv::{
+    csr,
+    sync::{LockClass, Mutex, MutexGuard},
+    trap::Trap,
+};
+
+/// The page table of a process.
+///
+/// It has to use the same paging mode as the kernel, so the kernel half can be shared.
+pub type UserPageTable = PageTable<KernelMode>;
+
+/// Counter to generate unique process ids.
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+/// The lock class of the address spaces of every process.
+const SPACE_CLASS: LockClass = LockClass::new("AddressSpace");
+
+/// A unique identifier for a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pid(u64);
+
+impl From<Pid> for u64 {
+    fn from(pid: Pid) -> u64 {
+        pid.0
+    }
+}
+
+/// A process, which is an address space that is shared by one or more threads.
+pub struct Process {
+    pid: Pid,
+    space: Mutex<AddressSpace>,
+    /// The raw `satp` value for the page table, which never changes after creation.
+    satp: usize,
+}
+
+impl Process {
+    /// Create a new process with an empty user address space.
+    pub fn new() -> Arc<Self> {
+        Self::with_address_space(AddressSpace::new())
+    }
+
+    fn with_address_space(space: AddressSpace) -> Arc<Self> {
+        Arc::new(Self {
+            pid: Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed)),
+            satp: space.table().satp().as_bits(),
+            space: Mutex::with_class(space, SPACE_CLASS),
+        })
+    }
+
+    /// Create a new process for the given ELF executable, and spawn its main thread
+    /// on the current hart.
+    pub fn exec(
+        data: &[u8],
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<(Arc<Self>, JoinHandle), elf::Error> {
+        // the file has to stay alive, because the segments are loaded lazily
+        let file = Arc::<[u8]>::from(data);
+        let elf = Elf::parse(&file)?;
+        let process = Self::new();
+
+        let (entry, stack) = {
+            let mut space = process.address_space();
+            let image = elf::load(&mut space, &elf, &file, USER_PIE_BASE)?;
+            let stack = elf::setup_stack(&mut space, &image, argv, envp)?;
+            (image.entry, stack)
+        };
+
+        let thread = process
+            .spawn_thread(entry, stack)
+            .map_err(elf::Error::Page)?;
+        Ok((process, thread))
+    }
+
+    /// Return the id of this process.
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Get exclusive access to the address space of this process.
+    pub fn address_space(&self) -> MutexGuard<'_, AddressSpace> {
+        self.space.lock()
+    }
+
+    /// Create a new process, whose address space is a copy-on-write copy of this process.
+    ///
+    /// No thread is spawned inside the new process.
+    pub fn fork(&self) -> Result<Arc<Self>, Fault> {
+        let space = self.address_space().fork()?;
+        Ok(Self::with_address_space(space))
+    }
+
+    /// Spawn a new thread inside this process on the current hart, that starts executing
+    /// in user mode at `entry`, with the stack pointer set to `stack`.
+    pub fn spawn_thread(
+        self: &Arc<Self>,
+        entry: VirtAddr,
+        stack: VirtAddr,
+    ) -> Result<JoinHandle, page::Error> {
+        thread::spawn_in(Arc::clone(self), move || unsafe {
+            enter_user(entry, stack)
+        })
+    }
+}
+
+/// Switch to the address space of the given process,
+/// or to the kernel address space if there's no process.
+pub(crate) unsafe fn activate(process: Option<&Process>) {
+    let satp = process.map_or_else(page::kernel_satp, |p| p.satp);
+
+    let current: usize;
+    asm!("csrr {}, satp", out(reg) current);
+    if current == satp {
+        return;
+    }
+
+    crate::tlb::activate(satp);
+}
+
+/// Handle a page fault that happened in user mode, inside the current process.
+///
+/// If the fault can't be resolved, a report is logged and the error is returned.
+pub(crate) fn handle_page_fault(trap: Trap, addr: usize, pc: usize) -> Result<(), Fault> {
+    let process = thread::current_process().expect("page fault in a kernel thread");
+    let access = Access::from_trap(trap).expect("not a page fault");
+
+    let mut space = process.address_space();
+    space.handle_fault(addr, access).map_err(|fault| {
+        report_fault(&process, &space, access, addr, pc, &fault);
+        fault
+    })
+}
+
+/// Log everything that is known about an unresolved page fault at `addr`.
+fn report_fault(
+    process: &Process,
+    space: &AddressSpace,
+    access: Access,
+    addr: usize,
+    pc: usize,
+    fault: &Fault,
+) {
+    let reason = match fault {
+        Fault::Unmapped => "the address is not inside any memory area",
+        Fault::StackOverflow => "stack overflow",
+        Fault::Permission(_) => "the memory area doesn't allow this access",
+        Fault::Alloc(_) | Fault::Page(_) => "failed to map the page",
+    };
+
+    log::warn!(
+        "{} page fault in process {}: {} of {:#x} at pc {:#x}: {} ({:?})",
+        "Unresolved".yellow(),
+        u64::from(process.pid()),
+        access,
+        addr,
+        pc,
+        reason,
+        fault
+    );
+
+    match space.table().translate(VirtAddr::from(addr)) {
+        Some((paddr, size, flags)) => {
+            log::warn!("  page:  {:p} ({:?}) with flags {}", paddr, size, flags)
+        }
+        None => log::warn!("  page:  not mapped"),
+    }
+
+    match space.vmas().find(addr) {
+        Some(vma) => log::warn!(
+            "  area:  {:#x}..{:#x} with flags {} ({:?})",
+            vma.start(),
+            vma.end(),
+            vma.flags(),
+            vma.kind()
+        ),
+        None => log::warn!("  area:  none"),
+    }
+}
+
+/// Run `f` for every part of the user memory range `vaddr..vaddr + len` that is inside
+/// a single page, together with the offset of the part inside the range.
+///
+/// Every page of the range must be mapped with the `USER` flag and all of the given `flags`,
+/// otherwise [`page::Error::InvalidAddress`] is returned. The memory is accessed through the
+/// physical memory window, so it doesn't matter which address space is active.
+pub fn with_user_memory(
+    table: &UserPageTable,
+    vaddr: usize,
+    len: usize,
+    flags: Flags,
+    mut f: impl FnMut(&mut [u8], usize),
+) -> Result<(), page::Error> {
+    let end = vaddr.checked_add(len).ok_or(page::Error::InvalidAddress)?;
+    if end > memmap::layout().user_space_end() {
+        return Err(page::Error::InvalidAddress);
+    }
+
+    // check the whole range first, so `f` is never called for a partially valid range
+    let pages = (vaddr & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE);
+    for page in pages.clone() {
+        match table.translate(VirtAddr::from(page)) {
+            Some((_, _, found)) if found.contains(flags | Flags::USER) => {}
+            _ => return Err(page::Error::InvalidAddress),
+        }
+    }
+
+    let mut off = 0;
+    while off < len {
+        let addr = vaddr + off;
+        let (paddr, _, _) = table
+            .translate(VirtAddr::from(addr))
+            .ok_or(page::Error::InvalidAddress)?;
+
+        let chunk = cmp::min(len - off, PAGE_SIZE - addr % PAGE_SIZE);
+        let mem = unsafe { slice::from_raw_parts_mut(phys2virt(paddr).as_ptr::<u8>(), chunk) };
+
+        f(mem, off);
+        off += chunk;
+    }
+
+    Ok(())
+}
+
+/// Leave the kernel and start executing user code at `pc`, with the stack pointer set to `sp`.
+unsafe fn enter_user(pc: VirtAddr, sp: VirtAddr) -> ! {
+    // interrupts must stay disabled until we are in user mode. `sret` will enable them
+    csr::sstatus::clear(SSTATUS_SIE | SSTATUS_SPP);
+    csr::sstatus::set(SSTATUS_SPIE);
+
+    _enter_user(pc.into(), sp.into())
+}
+
+/// Clear every integer register except the stack pointer, so no kernel data is leaked
+/// into user mode, and jump to `pc`.
+///
+/// The floating point registers are left alone, because the FPU is turned off and they
+/// may still hold the state of another thread. The zeroed registers of the new thread
+/// are loaded by [`thread::enable_fpu`] on its first floating point instruction.
+#[naked]
+unsafe extern "C" fn _enter_user(_pc: usize, _sp: usize) -> ! {
+    asm!(
+        "
+        csrw sepc, a0
+        mv sp, a1
+
+        li x1, 0
+        li x3, 0
+        li x4, 0
+        li x5, 0
+        li x6, 0
+        li x7, 0
+        li x8, 0
+        li x9, 0
+        li x10, 0
+        li x11, 0
+        li x12, 0
+        li x13, 0
+        li x14, 0
+        li x15, 0
+        li x16, 0
+        li x17, 0
+        li x18, 0
+        li x19, 0
+        li x20, 0
+        li x21, 0
+        li x22, 0
+        li x23, 0
+        li x24, 0
+        li x25, 0
+        li x26, 0
+        li x27, 0
+        li x28, 0
+        li x29, 0
+        li x30, 0
+        li x31, 0
+
+        sret
+    ",
+        options(noreturn)
+    )
+}
@@ -0,0 +1,386 @@
+//! The user address space of a process, and the page fault handler that populates it.
+//!
+//! Pages are only allocated when a process touches them for the first time. If an address
+//! space is forked, every page is shared between both address spaces and mapped read-only.
+//! The first write to such a page creates a private copy, if the page is still shared.
+
+use super::{
+    vma::{Vma, Vmas},
+    UserPageTable,
+};
+use crate::{
+    allocator::{self, PAGE_SIZE},
+    memmap::{self, phys2virt},
+    page::{self, Flags, PageSize, PhysAddr, VirtAddr},
+    pmem,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{fmt, ptr::NonNull, slice};
+use riscv::{sync::Mutex, trap::Trap};
+
+/// The number of additional address spaces that map a physical page, for every page
+/// that is shared between multiple address spaces.
+static SHARED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// Return the lock statistics of the shared page counts.
+#[cfg(feature = "lock-stats")]
+pub(crate) fn lock_stats() -> &'static riscv::sync::stats::LockStats {
+    SHARED_PAGES.stats()
+}
+
+/// The kind of memory access that caused a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    /// Return the access that caused the given page fault trap.
+    pub fn from_trap(trap: Trap) -> Option<Self> {
+        match trap {
+            Trap::LoadPageFault => Some(Access::Read),
+            Trap::StorePageFault => Some(Access::Write),
+            Trap::InstructionPageFault => Some(Access::Execute),
+            _ => None,
+        }
+    }
+
+    /// The flag a page must have to allow this access.
+    pub fn required_flag(self) -> Flags {
+        match self {
+            Access::Read => Flags::READ,
+            Access::Write => Flags::WRITE,
+            Access::Execute => Flags::EXEC,
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => f.write_str("read"),
+            Access::Write => f.write_str("write"),
+            Access::Execute => f.write_str("execute"),
+        }
+    }
+}
+
+/// The reasons a page fault can't be resolved.
+#[derive(Debug)]
+pub enum Fault {
+    /// The address is not inside any area.
+    Unmapped,
+    /// The address is inside the guard page of a stack.
+    StackOverflow,
+    /// The area doesn't allow the access. Contains the flags of the area.
+    Permission(Flags),
+    Alloc(allocator::Error),
+    Page(page::Error),
+}
+
+/// The user half of an address space, which consists of the page table
+/// and the areas that describe its contents.
+pub struct AddressSpace {
+    table: UserPageTable,
+    vmas: Vmas,
+}
+
+impl AddressSpace {
+    /// Create an empty address space with its own ASID.
+    pub fn new() -> Self {
+        let mut table = UserPageTable::new();
+        table.share_from(
+            &page::root(),
+            VirtAddr::from(memmap::layout().user_space_end()),
+        );
+
+        // if we run out of ASIDs, the process has to share the ASID
+        // of the kernel, which requires a TLB flush on every switch
+        table.set_asid(page::asid::alloc().unwrap_or(0));
+
+        Self {
+            table,
+            vmas: Vmas::new(),
+        }
+    }
+
+    /// Return the page table of this address space.
+    pub fn table(&self) -> &UserPageTable {
+        &self.table
+    }
+
+    /// Return the areas of this address space.
+    pub fn vmas(&self) -> &Vmas {
+        &self.vmas
+    }
+
+    /// Add a new area to this address space.
+    ///
+    /// Returns [`page::Error::AlreadyMapped`] if it overlaps with an existing area.
+    pub fn map(&mut self, vma: Vma) -> Result<(), page::Error> {
+        self.vmas
+            .insert(vma)
+            .map_err(|_| page::Error::AlreadyMapped)
+    }
+
+    /// Remove every area inside `start..start + len`, and free the pages that are mapped there.
+    pub fn unmap(&mut self, start: usize, len: usize) {
+        // the pages can only be released, after they were flushed from every TLB
+        let mut shootdown = self.table.shootdown();
+        let mut unmapped = Vec::new();
+
+        for vma in self.vmas.remove(start, start + len) {
+            for page in (vma.start()..vma.end()).step_by(PAGE_SIZE) {
+                let vaddr = VirtAddr::from(page);
+                let paddr = match self.table.translate(vaddr) {
+                    Some((paddr, _, _)) => paddr,
+                    None => continue,
+                };
+
+                match self.table.unmap_with(vaddr, &mut shootdown) {
+                    Ok(_) => unmapped.push(paddr),
+                    Err(err) => log::warn!("{} to unmap user page: {:?}", "Failed".yellow(), err),
+                }
+            }
+        }
+
+        shootdown.flush();
+        unmapped.into_iter().for_each(release_page);
+    }
+
+    /// Give the page at `page` the permissions of its area, together with `flags`.
+    ///
+    /// The page is split off into its own area.
+    pub fn extend_flags(&mut self, page: usize, flags: Flags) -> Result<(), Fault> {
+        let vma = self
+            .vmas
+            .remove(page, page + PAGE_SIZE)
+            .pop()
+            .ok_or(Fault::Unmapped)?;
+        let vma = Vma::new(
+            page,
+            page + PAGE_SIZE,
+            vma.flags() | flags,
+            vma.kind().clone(),
+        );
+        let flags = vma.flags();
+        self.vmas.insert(vma).unwrap();
+
+        let vaddr = VirtAddr::from(page);
+        if let Some((_, _, old)) = self.table.translate(vaddr) {
+            self.table
+                .protect(vaddr..VirtAddr::from(page + PAGE_SIZE), old | flags)
+                .map_err(Fault::Page)?;
+        }
+
+        Ok(())
+    }
+
+    /// Make sure every page inside `addr..addr + len` is mapped and not shared with another
+    /// address space, so the kernel can write to it. The permissions of the areas are ignored.
+    pub fn populate(&mut self, addr: usize, len: usize) -> Result<(), Fault> {
+        for page in pages(addr, len)? {
+            match self.table.translate(VirtAddr::from(page)) {
+                Some((paddr, _, flags)) if is_shared(paddr) => {
+                    self.copy_page(page, paddr, flags)?
+                }
+                Some(_) => {}
+                None => {
+                    let vma = self.vmas.find(page).ok_or(Fault::Unmapped)?.clone();
+                    self.fill_page(page, &vma)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make sure every page inside `addr..addr + len` is mapped and allows the given access,
+    /// like the process itself accessed it.
+    pub fn fault_in(&mut self, addr: usize, len: usize, access: Access) -> Result<(), Fault> {
+        for page in pages(addr, len)? {
+            match self.table.translate(VirtAddr::from(page)) {
+                Some((_, _, flags)) if flags.contains(access.required_flag()) => {}
+                _ => self.handle_fault(page, access)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolve a page fault at `addr`, that was caused by the given access.
+    pub fn handle_fault(&mut self, addr: usize, access: Access) -> Result<(), Fault> {
+        let page = addr & !(PAGE_SIZE - 1);
+        let vma = self.vmas.find(addr).ok_or(Fault::Unmapped)?;
+
+        if vma.is_guard(addr) {
+            return Err(Fault::StackOverflow);
+        }
+
+        if !vma.flags().contains(access.required_flag()) {
+            return Err(Fault::Permission(vma.flags()));
+        }
+
+        match self.table.translate(VirtAddr::from(page)) {
+            // the area is writable, but the page is not, so this is a copy-on-write page
+            Some((paddr, _, flags)) if !flags.contains(access.required_flag()) => {
+                self.copy_page(page, paddr, flags)
+            }
+            // the page was already mapped by another thread of this process
+            Some(_) => {
+                riscv::asm::sfence(page, self.table.asid());
+                Ok(())
+            }
+            None => {
+                let vma = vma.clone();
+                self.fill_page(page, &vma)
+            }
+        }
+    }
+
+    /// Create a copy of this address space, that shares every page with this address space
+    /// until one of them writes to it.
+    pub fn fork(&mut self) -> Result<Self, Fault> {
+        let mut child = Self::new();
+
+        for vma in self.vmas.iter() {
+            child.vmas.insert(vma.clone()).unwrap();
+
+            for page in (vma.start()..vma.end()).step_by(PAGE_SIZE) {
+                let vaddr = VirtAddr::from(page);
+                let (paddr, _, flags) = match self.table.translate(vaddr) {
+                    Some(mapping) => mapping,
+                    None => continue,
+                };
+
+                // both address spaces must fault on the next write
+                let shared = flags - Flags::WRITE;
+                if flags.contains(Flags::WRITE) {
+                    self.table
+                        .protect(vaddr..VirtAddr::from(page + PAGE_SIZE), shared)
+                        .map_err(Fault::Page)?;
+                }
+
+                child
+                    .table
+                    .map(paddr, vaddr, PageSize::Kilopage, shared)
+                    .map_err(Fault::Page)?;
+                share_page(paddr);
+            }
+        }
+
+        Ok(child)
+    }
+
+    /// Allocate a new page for `page`, and fill it with the initial contents of the area.
+    fn fill_page(&mut self, page: usize, vma: &Vma) -> Result<(), Fault> {
+        // an area without any permissions only reserves the address range
+        if vma.flags().is_empty() {
+            return Err(Fault::Permission(vma.flags()));
+        }
+
+        let frame = pmem::zalloc().map_err(Fault::Alloc)?;
+        let paddr = PhysAddr::from(frame.as_ptr());
+        vma.fill(page, unsafe { page_memory(paddr) });
+
+        let flags = vma.flags() | Flags::USER | Flags::ACCESSED | Flags::DIRTY;
+        self.table
+            .map(paddr, VirtAddr::from(page), PageSize::Kilopage, flags)
+            .map_err(|err| {
+                let _ = unsafe { pmem::free(frame) };
+                Fault::Page(err)
+            })
+    }
+
+    /// Give this address space a private, writable copy of a page that may be shared.
+    fn copy_page(&mut self, page: usize, paddr: PhysAddr, flags: Flags) -> Result<(), Fault> {
+        let vaddr = VirtAddr::from(page);
+        let flags = match self.vmas.find(page) {
+            Some(vma) => vma.flags() | Flags::USER | Flags::ACCESSED | Flags::DIRTY,
+            None => flags,
+        };
+
+        // the last address space that uses the page can take it over
+        let new = if is_shared(paddr) {
+            let frame = pmem::alloc().map_err(Fault::Alloc)?;
+            let new = PhysAddr::from(frame.as_ptr());
+            unsafe { page_memory(new).copy_from_slice(page_memory(paddr)) };
+            new
+        } else {
+            paddr
+        };
+
+        self.table.unmap(vaddr).map_err(Fault::Page)?;
+        self.table
+            .map(new, vaddr, PageSize::Kilopage, flags)
+            .map_err(Fault::Page)?;
+        riscv::asm::sfence(page, self.table.asid());
+
+        if new != paddr {
+            release_page(paddr);
+        }
+        Ok(())
+    }
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        self.unmap(0, memmap::layout().user_space_end());
+
+        let asid = self.table.asid();
+        if asid != 0 {
+            page::asid::free(asid);
+        }
+    }
+}
+
+/// Return an iterator over every page inside `addr..addr + len`.
+fn pages(addr: usize, len: usize) -> Result<impl Iterator<Item = usize>, Fault> {
+    let end = addr.checked_add(len).ok_or(Fault::Unmapped)?;
+    if end > memmap::layout().user_space_end() {
+        return Err(Fault::Unmapped);
+    }
+
+    Ok((addr & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE))
+}
+
+/// Access the physical page at `paddr` through the physical memory window.
+unsafe fn page_memory<'a>(paddr: PhysAddr) -> &'a mut [u8] {
+    slice::from_raw_parts_mut(phys2virt(paddr).as_ptr::<u8>(), PAGE_SIZE)
+}
+
+/// Record that one more address space maps the given page.
+fn share_page(paddr: PhysAddr) {
+    *SHARED_PAGES.lock().entry(paddr.into()).or_insert(0) += 1;
+}
+
+/// Check if the given page is mapped by more than one address space.
+fn is_shared(paddr: PhysAddr) -> bool {
+    SHARED_PAGES.lock().contains_key(&usize::from(paddr))
+}
+
+/// Drop one reference to the given page, and free it if it was the last one.
+fn release_page(paddr: PhysAddr) {
+    let mut shared = SHARED_PAGES.lock();
+    let addr = usize::from(paddr);
+
+    match shared.get_mut(&addr) {
+        Some(1) => {
+            shared.remove(&addr);
+        }
+        Some(count) => *count -= 1,
+        None => {
+            drop(shared);
+            let frame = NonNull::new(addr as *mut u8).unwrap();
+            let _ = unsafe { pmem::free(frame) };
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! Virtual memory areas, which describe the mappings of a user address space.
+//!
+//! A VMA only describes what memory should be visible at a range of addresses. The pages
+//! themselves are allocated lazily, when the process touches them for the first time.
+
+use crate::{allocator::PAGE_SIZE, memmap, page::Flags};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{cmp, fmt};
+
+/// Describes what memory is backing a [`Vma`].
+#[derive(Clone)]
+pub enum VmaKind {
+    /// Memory that is zeroed when it's touched for the first time.
+    Anonymous,
+    /// Memory that is filled with the contents of a file.
+    ///
+    /// The first `skip` bytes of the area are zeroed, followed by `size` bytes
+    /// of `data`, starting at `offset`. The rest of the area is zeroed.
+    File {
+        data: Arc<[u8]>,
+        offset: usize,
+        skip: usize,
+        size: usize,
+    },
+    /// Anonymous memory for a stack, whose lowest page is a guard page that is never mapped.
+    Stack,
+}
+
+impl fmt::Debug for VmaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmaKind::Anonymous => f.write_str("Anonymous"),
+            // the file contents are not useful in a debug print
+            VmaKind::File {
+                offset, skip, size, ..
+            } => f
+                .debug_struct("File")
+                .field("offset", offset)
+                .field("skip", skip)
+                .field("size", size)
+                .finish_non_exhaustive(),
+            VmaKind::Stack => f.write_str("Stack"),
+        }
+    }
+}
+
+/// A virtual memory area, which is a page aligned range of the user address space.
+#[derive(Debug, Clone)]
+pub struct Vma {
+    start: usize,
+    end: usize,
+    flags: Flags,
+    kind: VmaKind,
+}
+
+impl Vma {
+    /// Create a new area that covers `start..end`.
+    ///
+    /// `flags` are the permissions every page inside the area is mapped with. An area
+    /// without any permission reserves the range, but every access to it will fault.
+    pub fn new(start: usize, end: usize, flags: Flags, kind: VmaKind) -> Self {
+        assert!(
+            start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0,
+            "virtual memory areas must be page aligned"
+        );
+        assert!(
+            start < end && end <= memmap::layout().user_space_end(),
+            "invalid area"
+        );
+
+        // a page that is writable must also be readable
+        let flags = if flags.contains(Flags::WRITE) {
+            flags | Flags::READ
+        } else {
+            flags
+        };
+
+        Self {
+            start,
+            end,
+            flags: flags & (Flags::READ | Flags::WRITE | Flags::EXEC),
+            kind,
+        }
+    }
+
+    /// The first address of this area.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// The address right after this area.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// The permissions of this area.
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    /// The memory that is backing this area.
+    pub fn kind(&self) -> &VmaKind {
+        &self.kind
+    }
+
+    /// Check if the given address is inside this area.
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    /// Check if the given address is inside the guard page of a stack.
+    pub fn is_guard(&self, addr: usize) -> bool {
+        matches!(self.kind, VmaKind::Stack) && (self.start..self.start + PAGE_SIZE).contains(&addr)
+    }
+
+    /// Fill the page at `page` with the initial contents of this area.
+    ///
+    /// `mem` must be zeroed already.
+    pub fn fill(&self, page: usize, mem: &mut [u8]) {
+        let (data, offset, skip, size) = match &self.kind {
+            VmaKind::File {
+                data,
+                offset,
+                skip,
+                size,
+            } => (data, *offset, *skip, *size),
+            _ => return,
+        };
+
+        // the part of the page, relative to the start of the area, that is backed by the file
+        let page_off = page - self.start;
+        let start = cmp::max(page_off, skip);
+        let end = cmp::min(page_off + mem.len(), skip + size);
+        if start >= end {
+            return;
+        }
+
+        let file = offset + (start - skip);
+        let src = data.get(file..file + (end - start)).unwrap_or(&[]);
+        mem[start - page_off..start - page_off + src.len()].copy_from_slice(src);
+    }
+
+    /// Split this area into two areas at the given address.
+    fn split(self, addr: usize) -> (Self, Self) {
+        debug_assert!(addr > self.start && addr < self.end && addr % PAGE_SIZE == 0);
+
+        let delta = addr - self.start;
+        let right = match &self.kind {
+            VmaKind::Anonymous => VmaKind::Anonymous,
+            // only the lower part keeps the guard page
+            VmaKind::Stack => VmaKind::Anonymous,
+            VmaKind::File {
+                data,
+                offset,
+                skip,
+                size,
+            } => {
+                let consumed = cmp::min(delta.saturating_sub(*skip), *size);
+                VmaKind::File {
+                    data: Arc::clone(data),
+                    offset: offset + consumed,
+                    skip: skip.saturating_sub(delta),
+                    size: size - consumed,
+                }
+            }
+        };
+
+        let right = Self {
+            start: addr,
+            end: self.end,
+            flags: self.flags,
+            kind: right,
+        };
+        let left = Self { end: addr, ..self };
+        (left, right)
+    }
+}
+
+/// The set of all areas inside an address space, which never overlap.
+#[derive(Debug, Default)]
+pub struct Vmas {
+    areas: BTreeMap<usize, Vma>,
+}
+
+impl Vmas {
+    /// Create an empty set of areas.
+    pub fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// Insert a new area. Returns the area back, if it overlaps with an existing area.
+    pub fn insert(&mut self, vma: Vma) -> Result<(), Vma> {
+        if !self.is_free(vma.start, vma.end) {
+            return Err(vma);
+        }
+
+        self.areas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    /// Find the area that contains the given address.
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        self.areas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// Check if no area overlaps with `start..end`.
+    pub fn is_free(&self, start: usize, end: usize) -> bool {
+        self.areas
+            .range(..end)
+            .next_back()
+            .map_or(true, |(_, vma)| vma.end <= start)
+    }
+
+    /// Find the lowest free range of `len` bytes, that starts at or above `from`.
+    pub fn find_free(&self, len: usize, from: usize) -> Option<usize> {
+        let mut addr = from;
+        for vma in self.areas.values().filter(|vma| vma.end > from) {
+            if addr.checked_add(len)? <= vma.start {
+                break;
+            }
+            addr = cmp::max(addr, vma.end);
+        }
+
+        (addr.checked_add(len)? <= memmap::layout().user_space_end()).then(|| addr)
+    }
+
+    /// Remove every part of an area that is inside `start..end`. Areas that are only partially
+    /// inside the range are split.
+    ///
+    /// Returns the parts that were removed.
+    pub fn remove(&mut self, start: usize, end: usize) -> Vec<Vma> {
+        let keys = self
+            .areas
+            .range(..end)
+            .rev()
+            .take_while(|(_, vma)| vma.end > start)
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+
+        let mut removed = Vec::with_capacity(keys.len());
+        for key in keys {
+            let mut vma = self.areas.remove(&key).unwrap();
+
+            if vma.start < start {
+                let (left, right) = vma.split(start);
+                self.areas.insert(left.start, left);
+                vma = right;
+            }
+
+            if vma.end > end {
+                let (left, right) = vma.split(end);
+                self.areas.insert(right.start, right);
+                vma = left;
+            }
+
+            removed.push(vma);
+        }
+
+        removed
+    }
+
+    /// Return an iterator over every area, sorted by their address.
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> + '_ {
+        self.areas.values()
+    }
+}
@@ -1,4 +1,6 @@
-//! Linker symbols
+//! Linker symbols, and the symbol table of the kernel.
+
+use core::fmt;
 
 /// The start address where everything is linked to.
 ///
@@ -35,3 +37,97 @@ linker_section!(bss_range, __bss_start, __bss_end);
 linker_section!(stack_range, __stack_start, __stack_end);
 
 linker_section!(rel_dyn_range, __rel_dyn_start, __rel_dyn_end);
+linker_section!(symbols_range, __symbols_start, __symbols_end);
+
+/// The magic at the start of the symbol table, that is generated by `xtask build`.
+const SYMBOLS_MAGIC: [u8; 4] = *b"KSYM";
+
+/// The size of the symbol table header, which is the magic and the number of symbols.
+const HEADER_SIZE: usize = 8;
+
+/// The size of a single entry inside the symbol table.
+const ENTRY_SIZE: usize = 16;
+
+/// A function of the kernel, that was found inside the embedded symbol table.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    /// The demangled name of the function, without the hash.
+    pub name: &'static str,
+    /// The address of the function inside the kernel ELF file.
+    pub addr: usize,
+    /// The size of the function in bytes, which may be `0` if it's unknown.
+    pub size: usize,
+}
+
+/// Return the embedded symbol table, or `None` if the kernel was built without one.
+fn symbol_table() -> Option<&'static [u8]> {
+    let (start, end) = symbols_range();
+    let table = unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) };
+
+    (table.len() >= HEADER_SIZE && table[..4] == SYMBOLS_MAGIC).then(|| table)
+}
+
+/// Read the little endian `u32` at the given offset of the table.
+fn read_u32(table: &[u8], off: usize) -> Option<u32> {
+    let bytes = table.get(off..off + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Read the symbol at the given index.
+///
+/// Every entry consists of the address relative to [`LINK_START`], the size, and the offset
+/// and length of the name inside the string table, which follows the entries.
+fn read_symbol(table: &'static [u8], count: usize, idx: usize) -> Option<Symbol> {
+    let off = HEADER_SIZE + idx * ENTRY_SIZE;
+    let addr = read_u32(table, off)? as usize;
+    let size = read_u32(table, off + 4)? as usize;
+    let name_off = read_u32(table, off + 8)? as usize;
+    let name_len = read_u32(table, off + 12)? as usize;
+
+    let strings = HEADER_SIZE + count * ENTRY_SIZE + name_off;
+    let name = table.get(strings..strings + name_len)?;
+
+    Some(Symbol {
+        name: core::str::from_utf8(name).ok()?,
+        addr: LINK_START + addr,
+        size,
+    })
+}
+
+/// Find the function that contains the given address of the running kernel.
+///
+/// Returns the function together with the offset of the address inside it.
+pub fn lookup(addr: usize) -> Option<(Symbol, usize)> {
+    let table = symbol_table()?;
+    let count = read_u32(table, 4)? as usize;
+    let addr = crate::backtrace::unrelocate(addr);
+
+    // the entries are sorted by their address, so search the last symbol
+    // that starts at or before the address
+    let (mut lo, mut hi) = (0, count);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if read_symbol(table, count, mid)?.addr <= addr {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+
+    let sym = read_symbol(table, count, lo.checked_sub(1)?)?;
+    let offset = addr - sym.addr;
+    (sym.size == 0 || offset < sym.size).then(|| (sym, offset))
+}
+
+/// Display helper that prints the function and offset of an address
+/// inside the running kernel, like `kernel::main+0x2a`.
+pub struct Display(pub usize);
+
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match lookup(self.0) {
+            Some((sym, offset)) => write!(f, "{}+{:#x}", sym.name, offset),
+            None => f.write_str("<unknown>"),
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! Synchronization primitives that put the current thread to sleep, instead of spinning.
+//!
+//! All of them are built on a [`WaitQueue`], which parks the current thread until another
+//! thread or an interrupt handler wakes it up. Waiting is only possible inside a thread with
+//! interrupts enabled, never inside a trap. Use the spinlocks from [`riscv::sync`] to protect
+//! data that is accessed by interrupt handlers.
+
+mod condvar;
+mod mutex;
+mod semaphore;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use wait_queue::WaitQueue;
@@ -0,0 +1,66 @@
+use super::{MutexGuard, WaitQueue};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A condition variable, that is used together with a sleeping [`Mutex`](super::Mutex).
+pub struct Condvar {
+    /// Incremented by every notification, so a waiter can see if it was notified
+    /// after it released the mutex.
+    seq: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    /// Create a new condition variable.
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Release the mutex of `guard` and sleep until this condition variable is notified.
+    /// The mutex is locked again before returning.
+    ///
+    /// Like every condition variable, this may wake up spuriously,
+    /// so the condition has to be checked in a loop.
+    pub fn wait<'lock, T: ?Sized>(&self, guard: MutexGuard<'lock, T>) -> MutexGuard<'lock, T> {
+        // the notifier has to take the mutex, so it can't notify before we read the counter
+        let seq = self.seq.load(Ordering::Acquire);
+        let mutex = guard.mutex();
+        drop(guard);
+
+        self.waiters
+            .wait_until(|| (self.seq.load(Ordering::Acquire) != seq).then(|| ()));
+        mutex.lock()
+    }
+
+    /// Sleep until `condition` returns `false`, releasing the mutex while sleeping.
+    pub fn wait_while<'lock, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'lock, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'lock, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wake up one thread that waits on this condition variable.
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Wake up all threads that wait on this condition variable.
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
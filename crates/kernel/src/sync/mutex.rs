@@ -0,0 +1,80 @@
+use super::WaitQueue;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A mutex that puts threads to sleep while it's locked.
+///
+/// In contrast to [`riscv::sync::Mutex`], this mutex can be held for a long time,
+/// but it can't be used inside interrupt handlers.
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Create a new mutex that holds the given value.
+    pub const fn new(val: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(val),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Lock this mutex, and sleep until it's available.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        match self.try_lock() {
+            Some(guard) => guard,
+            None => self.waiters.wait_until(|| self.try_lock()),
+        }
+    }
+
+    /// Try to lock this mutex, and return `None` if it's already locked.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+
+        Some(MutexGuard { mutex: self })
+    }
+}
+
+/// The guard providing protected access to the data of a [`Mutex`].
+pub struct MutexGuard<'lock, T: ?Sized> {
+    mutex: &'lock Mutex<T>,
+}
+
+impl<'lock, T: ?Sized> MutexGuard<'lock, T> {
+    /// Return the mutex this guard belongs to.
+    pub(super) fn mutex(&self) -> &'lock Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
@@ -0,0 +1,45 @@
+use super::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A counting semaphore, that puts threads to sleep until a permit is available.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    /// Create a new semaphore with the given number of permits.
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Take a permit, and sleep until one is available.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire().then(|| ()))
+    }
+
+    /// Try to take a permit, and return `false` if there's none available.
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Return a permit, and wake up a thread that waits for it.
+    ///
+    /// This can be called from inside an interrupt handler.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Return the number of permits that are available right now.
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{
+    hart,
+    thread::{self, Waker},
+    trap,
+};
+use alloc::vec::Vec;
+use riscv::sync::{IrqMutex, LockClass};
+
+/// The lock class of every wait queue.
+const CLASS: LockClass = LockClass::new("WaitQueue");
+
+/// A queue of threads that wait for a condition to become true.
+pub struct WaitQueue {
+    waiters: IrqMutex<Vec<Waker>>,
+}
+
+impl WaitQueue {
+    /// Create a new queue without any waiting threads.
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqMutex::with_class(Vec::new(), CLASS),
+        }
+    }
+
+    /// Put the current thread to sleep until `condition` returns `Some`.
+    ///
+    /// The condition is checked while the queue is locked, so a wakeup that happens between
+    /// checking the condition and going to sleep can't get lost. It must not block, and is
+    /// called again every time the thread is woken up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called inside a trap, or while interrupts are disabled.
+    #[track_caller]
+    pub fn wait_until<T>(&self, mut condition: impl FnMut() -> Option<T>) -> T {
+        assert!(
+            !hart::current().in_trap() && trap::interrupts_enabled(),
+            "can't sleep inside a trap or with interrupts disabled"
+        );
+
+        loop {
+            {
+                let mut waiters = self.waiters.lock();
+                if let Some(val) = condition() {
+                    return val;
+                }
+
+                waiters.push(thread::block_current());
+            }
+
+            // we are only switched out if nobody woke us up in the meantime
+            thread::yield_now();
+        }
+    }
+
+    /// Wake up the thread that waits the longest. Returns `false` if there was none.
+    ///
+    /// This can be called from inside an interrupt handler.
+    pub fn wake_one(&self) -> bool {
+        let waker = {
+            let mut waiters = self.waiters.lock();
+            (!waiters.is_empty()).then(|| waiters.remove(0))
+        };
+
+        match waker {
+            Some(waker) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake up all waiting threads, and return how many there were.
+    ///
+    /// This can be called from inside an interrupt handler.
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let count = waiters.len();
+        waiters.into_iter().for_each(Waker::wake);
+        count
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,285 @@
+//! The system call interface for user mode processes.
+//!
+//! The ABI follows Linux on RISC-V: the number of the system call is passed in `a7`,
+//! the arguments in `a0` to `a5`, and the result is returned in `a0`. A failed call
+//! returns the negated [`Errno`].
+
+use crate::{
+    allocator::{align_up, PAGE_SIZE},
+    memmap,
+    page::Flags,
+    process::{self, Access, AddressSpace, Process, Vma, VmaKind},
+    thread,
+    trap::TrapFrame,
+};
+use alloc::sync::Arc;
+use core::{cmp, str, time::Duration};
+
+pub const SYS_WRITE: usize = 64;
+pub const SYS_EXIT: usize = 93;
+pub const SYS_EXIT_GROUP: usize = 94;
+pub const SYS_NANOSLEEP: usize = 101;
+pub const SYS_SCHED_YIELD: usize = 124;
+pub const SYS_GETPID: usize = 172;
+pub const SYS_MUNMAP: usize = 215;
+pub const SYS_MMAP: usize = 222;
+
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+const STDOUT: usize = 1;
+const STDERR: usize = 2;
+
+/// The error codes a system call can return, which use the same values as Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Errno {
+    /// The file descriptor is invalid.
+    EBADF = 9,
+    /// There's not enough memory left.
+    ENOMEM = 12,
+    /// A pointer argument points to memory that is not accessible.
+    EFAULT = 14,
+    /// An argument is invalid.
+    EINVAL = 22,
+    /// The system call is not implemented.
+    ENOSYS = 38,
+}
+
+type Handler = fn(&Arc<Process>, [usize; 6]) -> Result<usize, Errno>;
+
+/// Every supported system call, together with its number.
+const SYSCALLS: &[(usize, Handler)] = &[
+    (SYS_WRITE, sys_write),
+    (SYS_EXIT, sys_exit),
+    (SYS_EXIT_GROUP, sys_exit),
+    (SYS_NANOSLEEP, sys_nanosleep),
+    (SYS_SCHED_YIELD, sys_sched_yield),
+    (SYS_GETPID, sys_getpid),
+    (SYS_MUNMAP, sys_munmap),
+    (SYS_MMAP, sys_mmap),
+];
+
+/// Handle an `ecall` instruction that was executed in user mode.
+///
+/// Returns the new `sepc`, which points behind the `ecall` instruction.
+pub(crate) fn handle(frame: &mut TrapFrame, sepc: usize) -> usize {
+    let regs = &frame.xregs;
+    let nr = regs.a7;
+    let args = [regs.a0, regs.a1, regs.a2, regs.a3, regs.a4, regs.a5];
+
+    let process = thread::current_process().expect("system call from a kernel thread");
+
+    let result = match SYSCALLS.iter().find(|(num, _)| *num == nr) {
+        Some((_, handler)) => handler(&process, args),
+        None => {
+            log::warn!("{} system call {} at {:#x?}", "Unknown".yellow(), nr, sepc);
+            Err(Errno::ENOSYS)
+        }
+    };
+
+    frame.xregs.a0 = match result {
+        Ok(val) => val,
+        Err(errno) => (errno as usize).wrapping_neg(),
+    };
+
+    sepc + 4
+}
+
+/// `write(fd, buf, len)`
+///
+/// Only stdout and stderr are supported, which both go to the console.
+/// Invalid UTF-8 is replaced by `U+FFFD`.
+fn sys_write(process: &Arc<Process>, [fd, buf, len, ..]: [usize; 6]) -> Result<usize, Errno> {
+    if fd != STDOUT && fd != STDERR {
+        return Err(Errno::EBADF);
+    }
+
+    let mut space = process.address_space();
+    let mut chunk = [0u8; 256];
+    // the number of bytes at the start of `chunk`, that are an incomplete character
+    // from the previous chunk
+    let mut pending = 0;
+    let mut off = 0;
+
+    while off < len {
+        let count = cmp::min(len - off, chunk.len() - pending);
+        read_user(&mut space, buf + off, &mut chunk[pending..pending + count])?;
+        off += count;
+
+        let end = pending + count;
+        let mut pos = 0;
+        pending = 0;
+
+        while pos < end {
+            match str::from_utf8(&chunk[pos..end]) {
+                Ok(s) => {
+                    console(s);
+                    pos = end;
+                }
+                Err(err) => {
+                    let valid = pos + err.valid_up_to();
+                    console(unsafe { str::from_utf8_unchecked(&chunk[pos..valid]) });
+
+                    match err.error_len() {
+                        Some(invalid) => {
+                            console("\u{FFFD}");
+                            pos = valid + invalid;
+                        }
+                        // the character may continue in the next chunk
+                        None if off < len => {
+                            chunk.copy_within(valid..end, 0);
+                            pending = end - valid;
+                            pos = end;
+                        }
+                        None => {
+                            console("\u{FFFD}");
+                            pos = end;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(len)
+}
+
+/// `exit(code)` and `exit_group(code)`
+///
+/// The threads of a process are not tracked, so both only end the calling thread.
+fn sys_exit(_: &Arc<Process>, [code, ..]: [usize; 6]) -> Result<usize, Errno> {
+    thread::kill_current(code);
+    Ok(0)
+}
+
+/// `nanosleep(req, rem)`
+///
+/// A sleep can't be interrupted, so `rem` is never written.
+fn sys_nanosleep(process: &Arc<Process>, [req, ..]: [usize; 6]) -> Result<usize, Errno> {
+    let mut timespec = [0u8; 16];
+    read_user(&mut process.address_space(), req, &mut timespec)?;
+
+    let mut secs = [0u8; 8];
+    let mut nanos = [0u8; 8];
+    secs.copy_from_slice(&timespec[..8]);
+    nanos.copy_from_slice(&timespec[8..]);
+    let (secs, nanos) = (i64::from_le_bytes(secs), i64::from_le_bytes(nanos));
+
+    if secs < 0 || !(0..1_000_000_000).contains(&nanos) {
+        return Err(Errno::EINVAL);
+    }
+
+    thread::sleep_current(Duration::new(secs as u64, nanos as u32));
+    Ok(0)
+}
+
+/// `sched_yield()`
+fn sys_sched_yield(_: &Arc<Process>, _: [usize; 6]) -> Result<usize, Errno> {
+    thread::reschedule();
+    Ok(0)
+}
+
+/// `getpid()`
+fn sys_getpid(process: &Arc<Process>, _: [usize; 6]) -> Result<usize, Errno> {
+    Ok(u64::from(process.pid()) as usize)
+}
+
+/// `mmap(addr, len, prot, flags, fd, offset)`
+///
+/// Only private anonymous mappings are supported, whose pages are allocated when they
+/// are accessed. Without `MAP_FIXED`, `addr` is ignored.
+///
+/// Shared mappings are rejected, because `fork` gives the child a copy-on-write view
+/// of every mapping.
+fn sys_mmap(
+    process: &Arc<Process>,
+    [addr, len, prot, flags, ..]: [usize; 6],
+) -> Result<usize, Errno> {
+    if flags & MAP_ANONYMOUS == 0 || flags & MAP_SHARED != 0 {
+        return Err(Errno::ENOSYS);
+    }
+
+    if len == 0 || flags & MAP_PRIVATE == 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let len = len.checked_add(PAGE_SIZE - 1).ok_or(Errno::ENOMEM)? & !(PAGE_SIZE - 1);
+    let mut space = process.address_space();
+
+    let start = if flags & MAP_FIXED != 0 {
+        if addr % PAGE_SIZE != 0 || !is_user_range(addr, len) {
+            return Err(Errno::EINVAL);
+        }
+
+        // fixed mappings replace everything that is already mapped
+        space.unmap(addr, len);
+        addr
+    } else {
+        space
+            .vmas()
+            .find_free(len, memmap::layout().user_mmap_base)
+            .ok_or(Errno::ENOMEM)?
+    };
+
+    // an area without any permission only reserves the range
+    let mut page_flags = Flags::empty();
+    if prot & PROT_READ != 0 {
+        page_flags |= Flags::READ;
+    }
+    if prot & PROT_WRITE != 0 {
+        page_flags |= Flags::WRITE;
+    }
+    if prot & PROT_EXEC != 0 {
+        page_flags |= Flags::EXEC;
+    }
+
+    space
+        .map(Vma::new(start, start + len, page_flags, VmaKind::Anonymous))
+        .map_err(|_| Errno::ENOMEM)?;
+    Ok(start)
+}
+
+/// `munmap(addr, len)`
+fn sys_munmap(process: &Arc<Process>, [addr, len, ..]: [usize; 6]) -> Result<usize, Errno> {
+    if addr % PAGE_SIZE != 0 || len == 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let len = align_up(len, PAGE_SIZE);
+    if !is_user_range(addr, len) {
+        return Err(Errno::EINVAL);
+    }
+
+    process.address_space().unmap(addr, len);
+    Ok(0)
+}
+
+/// Check if the range `addr..addr + len` is inside the user half of the address space.
+fn is_user_range(addr: usize, len: usize) -> bool {
+    addr.checked_add(len)
+        .map_or(false, |end| end <= memmap::layout().user_space_end())
+}
+
+/// Copy the user memory at `vaddr` into `buf`.
+fn read_user(space: &mut AddressSpace, vaddr: usize, buf: &mut [u8]) -> Result<(), Errno> {
+    space
+        .fault_in(vaddr, buf.len(), Access::Read)
+        .map_err(|_| Errno::EFAULT)?;
+
+    process::with_user_memory(space.table(), vaddr, buf.len(), Flags::READ, |mem, off| {
+        buf[off..off + mem.len()].copy_from_slice(mem)
+    })
+    .map_err(|_| Errno::EFAULT)
+}
+
+/// Write the given string to the console.
+fn console(s: &str) {
+    let _ = log::write(s);
+}
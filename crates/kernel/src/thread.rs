@@ -0,0 +1,659 @@
+//! Kernel threads and the round-robin scheduler.
+//!
+//! Every hart has its own run queue, and a thread always stays on the hart it was spawned on.
+//! The code that booted a hart (`main` or `hmain`) becomes the first thread of that hart.
+//!
+//! Switching to another thread only happens at the end of a trap. The registers that were saved
+//! by the trap handler are moved into the current thread, and the [`TrapFrame`] is replaced with
+//! the registers of the next thread, which are then restored by the trap handler. Threads are
+//! preempted by the timer interrupt every [`TIME_SLICE`], and [`yield_now`] raises a software
+//! interrupt on the current hart to give up the rest of the time slice. If there's no thread
+//! that can run, the hart runs its idle thread, which waits for the next interrupt.
+//!
+//! A thread may belong to a [`Process`], in which case the address space of the process
+//! is activated whenever the thread is switched in.
+//!
+//! The FPU is turned off for every thread, until it uses the FPU for the first time. The floating
+//! point registers are only saved if a thread modified them, and they are loaded lazily when a
+//! thread uses the FPU again after another thread used it.
+//!
+//! Note that `#[thread_local]` variables are local to each hart, not to each thread.
+
+use crate::{
+    allocator::{self, PAGE_SIZE},
+    hart,
+    memmap::{self, STACK_GUARD_SIZE, THREAD_STACK_SIZE},
+    page::{self, Flags, PageSize, VirtAddr},
+    process::{self, Process},
+    time,
+    trap::{
+        self, TrapFrame, SIP_SSIP, SSTATUS_FS, SSTATUS_FS_CLEAN, SSTATUS_FS_DIRTY, SSTATUS_SIE,
+        SSTATUS_SPIE, SSTATUS_SPP,
+    },
+};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use riscv::csr;
+#[cfg(debug_assertions)]
+use riscv::sync::lockdep;
+use riscv::sync::IrqMutex;
+
+/// The time a thread can run, before it's preempted by the next thread.
+pub const TIME_SLICE: Duration = Duration::from_millis(10);
+
+/// Counter to generate unique thread ids.
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A unique identifier for a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        Self(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The state of a thread that is shared with its [`JoinHandle`].
+struct Shared {
+    id: ThreadId,
+    exited: AtomicBool,
+    exit_code: AtomicUsize,
+}
+
+impl Shared {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            id: ThreadId::new(),
+            exited: AtomicBool::new(false),
+            exit_code: AtomicUsize::new(0),
+        })
+    }
+}
+
+/// The maximum number of thread stacks that can exist at the same time.
+const MAX_STACKS: usize = 64 * 1024;
+
+/// Bitmap of every slot inside the thread stack region that is in use.
+static STACK_SLOTS: IrqMutex<[u64; MAX_STACKS / 64]> = IrqMutex::new([0; MAX_STACKS / 64]);
+
+/// Return the lock statistics of the thread stack bitmap.
+#[cfg(feature = "lock-stats")]
+pub(crate) fn lock_stats() -> &'static riscv::sync::stats::LockStats {
+    STACK_SLOTS.stats()
+}
+
+/// The stack of a spawned thread, which is unmapped and freed when dropped.
+struct Stack {
+    start: VirtAddr,
+    slot: usize,
+}
+
+impl Stack {
+    /// Allocate a free slot inside the thread stack region, and map the stack into it.
+    fn alloc() -> Result<Self, page::Error> {
+        let slot = alloc_slot().ok_or(page::Error::Alloc(allocator::Error::NoMemoryAvailable))?;
+
+        // every slot begins with an unmapped guard page
+        let start =
+            memmap::stack_start(memmap::layout().thread_stack_base, THREAD_STACK_SIZE, slot);
+        let start = VirtAddr::from(start);
+
+        let res = page::root().map_alloc(
+            start,
+            THREAD_STACK_SIZE / PAGE_SIZE,
+            PageSize::Kilopage,
+            Flags::READ | Flags::WRITE | Flags::ACCESSED | Flags::DIRTY,
+        );
+
+        if let Err(err) = res {
+            free_slot(slot);
+            return Err(err);
+        }
+
+        Ok(Self { start, slot })
+    }
+
+    /// Return the end address of this stack, which is the initial stack pointer.
+    fn end(&self) -> usize {
+        usize::from(self.start) + THREAD_STACK_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        if let Err(err) = unsafe { page::root().free(self.start, THREAD_STACK_SIZE / PAGE_SIZE) } {
+            log::warn!("{} to free thread stack: {:?}", "Failed".yellow(), err);
+            return;
+        }
+
+        free_slot(self.slot);
+    }
+}
+
+/// Find an unused slot inside the thread stack region, that ends before the
+/// [`vmem_alloc_base`](memmap::Layout::vmem_alloc_base).
+fn alloc_slot() -> Option<usize> {
+    let layout = memmap::layout();
+    let slots = (layout.vmem_alloc_base - layout.thread_stack_base)
+        / (THREAD_STACK_SIZE + STACK_GUARD_SIZE);
+
+    let mut used = STACK_SLOTS.lock();
+    let slot =
+        (0..slots.min(MAX_STACKS)).find(|&slot| used[slot / 64] & (1 << (slot % 64)) == 0)?;
+
+    used[slot / 64] |= 1 << (slot % 64);
+    Some(slot)
+}
+
+/// Give a slot of the thread stack region back, after its stack was unmapped.
+fn free_slot(slot: usize) {
+    STACK_SLOTS.lock()[slot / 64] &= !(1 << (slot % 64));
+}
+
+/// A thread as seen by the scheduler.
+struct Task {
+    shared: Arc<Shared>,
+    /// The registers of this thread, while it's not running.
+    ///
+    /// The floating point registers are only up to date, if this thread
+    /// doesn't own the FPU of the hart.
+    frame: TrapFrame,
+    /// The program counter of this thread, while it's not running.
+    pc: usize,
+    /// The process this thread belongs to, or `None` for a pure kernel thread.
+    process: Option<Arc<Process>>,
+    /// The stack of this thread, which is `None` for the thread
+    /// that is running on the boot stack of a hart.
+    stack: Option<Stack>,
+    /// Set while the thread is sleeping, and must not be scheduled.
+    blocked: bool,
+    /// Set for the idle thread of a hart.
+    idle: bool,
+    /// The locks this thread holds, while it's not running.
+    #[cfg(debug_assertions)]
+    held: lockdep::HeldLocks,
+}
+
+impl Task {
+    /// Create a new thread that starts executing `entry` with `arg` as the first argument.
+    fn new(process: Option<Arc<Process>>, entry: usize, arg: usize) -> Result<Self, page::Error> {
+        let shared = Shared::new();
+        let stack = Stack::alloc()?;
+
+        let mut frame = TrapFrame::default();
+        frame.xregs.sp = stack.end();
+        frame.xregs.a0 = arg;
+
+        // the thread starts in supervisor mode, with interrupts enabled
+        frame.sstatus = SSTATUS_SPP | SSTATUS_SPIE;
+
+        // the thread runs on this hart, so it uses the same global and hart local pointer
+        unsafe {
+            asm!("mv {}, gp", out(reg) frame.xregs.gp);
+            asm!("mv {}, tp", out(reg) frame.xregs.tp);
+        }
+
+        Ok(Self {
+            shared,
+            frame,
+            pc: entry,
+            process,
+            stack: Some(stack),
+            blocked: false,
+            idle: false,
+            #[cfg(debug_assertions)]
+            held: lockdep::HeldLocks::new(),
+        })
+    }
+}
+
+/// The per-hart scheduler, which runs all threads of a hart in a round-robin fashion.
+pub struct Scheduler {
+    /// The thread that is running right now.
+    current: Option<Box<Task>>,
+    /// All threads that are waiting to run, including the ones that are blocked.
+    ready: VecDeque<Box<Task>>,
+    /// The thread that runs if no other thread can run.
+    idle: Option<Box<Task>>,
+    /// All threads that exited, but their stack was not freed yet.
+    ///
+    /// Freeing a stack requires the page table lock, so it can't be done inside a trap.
+    dead: Vec<Task>,
+    /// Set if the current thread should be switched at the end of the next trap.
+    need_resched: bool,
+    /// The thread whose floating point registers are loaded into the FPU.
+    fpu_owner: Option<ThreadId>,
+}
+
+impl Scheduler {
+    /// Create a new scheduler, that has no threads.
+    pub fn new() -> Self {
+        Self {
+            current: None,
+            ready: VecDeque::new(),
+            idle: None,
+            dead: Vec::new(),
+            need_resched: false,
+            fpu_owner: None,
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `f` with the scheduler of this hart, while interrupts are disabled.
+fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    f(&mut hart::current().scheduler().lock())
+}
+
+/// Turn the code that is currently running on this hart into the first thread
+/// of this hart, and start preempting threads.
+pub fn init() {
+    let task = Box::new(Task {
+        shared: Shared::new(),
+        frame: TrapFrame::default(),
+        pc: 0,
+        process: None,
+        stack: None,
+        blocked: false,
+        idle: false,
+        #[cfg(debug_assertions)]
+        held: lockdep::HeldLocks::new(),
+    });
+
+    let mut idle = Task::new(None, idle_entry as usize, 0).expect("failed to create idle thread");
+    idle.idle = true;
+    let idle = Box::new(idle);
+
+    with_scheduler(|sched| {
+        sched.current = Some(task);
+        sched.idle = Some(idle);
+    });
+
+    time::every(TIME_SLICE, || {
+        with_scheduler(|sched| sched.need_resched = true)
+    });
+}
+
+/// Free the stacks of all threads on this hart that exited.
+fn reap() {
+    let dead = with_scheduler(|sched| core::mem::take(&mut sched.dead));
+    drop(dead);
+}
+
+/// Switch to the next thread, if a reschedule was requested.
+///
+/// `frame` and `sepc` are the registers of the interrupted code, which will be replaced
+/// by the registers of the next thread. Returns the new `sepc` value.
+///
+/// This is called at the end of every trap.
+pub(crate) fn schedule(frame: &mut TrapFrame, sepc: usize) -> usize {
+    let mut sched = hart::current().scheduler().lock();
+    if !core::mem::take(&mut sched.need_resched) {
+        return sepc;
+    }
+
+    // the scheduler of this hart was not initialized yet
+    let exited = match &sched.current {
+        Some(current) => current.shared.exited.load(Ordering::Acquire),
+        None => return sepc,
+    };
+
+    // a thread that exited or is blocked can't continue running,
+    // so it's replaced by the idle thread if there's no other thread
+    let must_leave = exited || sched.current.as_ref().map_or(false, |c| c.blocked);
+    let next = match sched.ready.iter().position(|task| !task.blocked) {
+        Some(idx) => sched.ready.remove(idx).unwrap(),
+        None if must_leave => match sched.idle.take() {
+            Some(idle) => idle,
+            None => return sepc,
+        },
+        None => return sepc,
+    };
+
+    // save the registers of the current thread. the floating point registers
+    // were only saved by the trap handler, if they were modified
+    let mut current = sched.current.take().unwrap();
+    current.frame.xregs.clone_from(&frame.xregs);
+    if frame.sstatus & SSTATUS_FS == SSTATUS_FS_DIRTY {
+        current.frame.fregs.clone_from(&frame.fregs);
+        frame.sstatus = (frame.sstatus & !SSTATUS_FS) | SSTATUS_FS_CLEAN;
+    }
+    current.frame.sstatus = frame.sstatus;
+    current.pc = sepc;
+
+    // a preempted thread keeps its locks, only the scheduler lock stays with the hart
+    #[cfg(debug_assertions)]
+    lockdep::switch(&mut current.held, &next.held, 1);
+
+    if current.idle {
+        sched.idle = Some(current);
+    } else if exited {
+        sched.dead.push(*current);
+    } else {
+        sched.ready.push_back(current);
+    }
+
+    // and load the registers of the next thread
+    frame.xregs.clone_from(&next.frame.xregs);
+    frame.sstatus = next.frame.sstatus;
+    let pc = next.pc;
+
+    // if another thread used the FPU in the meantime, it's turned off
+    // and the registers are loaded once the next thread uses it again
+    if sched.fpu_owner != Some(next.shared.id) {
+        frame.sstatus &= !SSTATUS_FS;
+    }
+
+    // `sret` will return into the privilege mode of the next thread, which is stored inside
+    // the frame, so only the address space has to be switched
+    unsafe { process::activate(next.process.as_deref()) };
+
+    sched.current = Some(next);
+
+    pc
+}
+
+/// Turn on the FPU for the current thread, after it tried to use the FPU while it was turned off.
+///
+/// `frame` are the registers of the current thread, that are restored at the end of the trap.
+pub(crate) fn enable_fpu(frame: &mut TrapFrame) {
+    with_scheduler(|sched| {
+        let current = sched
+            .current
+            .as_ref()
+            .expect("scheduler not yet initialized");
+
+        // the registers of a thread that never used the FPU are zeroed
+        unsafe { trap::load_fregs(&current.frame.fregs) };
+        sched.fpu_owner = Some(current.shared.id);
+    });
+
+    frame.sstatus |= SSTATUS_FS_CLEAN;
+}
+
+/// Give up the rest of the time slice of the current thread.
+///
+/// If interrupts are disabled, the switch happens once they are enabled again.
+pub fn yield_now() {
+    with_scheduler(|sched| sched.need_resched = true);
+
+    // raise a software interrupt on this hart, which will end up in `schedule`
+    unsafe { csr::sip::set(SIP_SSIP) };
+}
+
+/// Request a switch to the next thread at the end of the current trap.
+pub(crate) fn reschedule() {
+    with_scheduler(|sched| sched.need_resched = true);
+}
+
+/// Block the current thread until `duration` passed.
+///
+/// The thread keeps running until the next reschedule, which is requested by this function.
+fn block_for(duration: Duration) {
+    trap::without_interrupts(|| {
+        let id = with_scheduler(|sched| {
+            let current = sched
+                .current
+                .as_mut()
+                .expect("scheduler not yet initialized");
+            current.blocked = true;
+            let id = current.shared.id;
+
+            sched.need_resched = true;
+            id
+        });
+
+        // the timer can't fire before the thread is marked as blocked,
+        // because interrupts are disabled
+        time::after(duration, move || wake(id));
+    });
+}
+
+/// Wake up the blocked thread with the given id on this hart.
+fn wake(id: ThreadId) {
+    wake_on(hart::current(), id);
+}
+
+/// Wake up the blocked thread with the given id on the given hart.
+///
+/// Returns `true` if the hart is idle and must be interrupted to switch to the thread.
+fn wake_on(ctx: &hart::HartContext, id: ThreadId) -> bool {
+    let mut guard = ctx.scheduler().lock();
+    let sched = &mut *guard;
+    let task = sched
+        .current
+        .iter_mut()
+        .chain(sched.ready.iter_mut())
+        .find(|task| task.shared.id == id);
+
+    if let Some(task) = task {
+        task.blocked = false;
+    }
+
+    // switch away from the idle thread as soon as possible
+    let idle = sched.current.as_ref().map_or(false, |c| c.idle);
+    if idle {
+        sched.need_resched = true;
+    }
+    idle
+}
+
+/// A handle that wakes up a blocked thread, which may run on another hart.
+#[derive(Debug)]
+pub struct Waker {
+    hart: u64,
+    thread: ThreadId,
+}
+
+impl Waker {
+    /// Wake up the thread. This can be called from inside an interrupt handler.
+    pub fn wake(self) {
+        let ctx = match hart::get(self.hart) {
+            Some(ctx) => ctx,
+            None => return,
+        };
+
+        // a hart that sits in its idle thread must be woken up by an IPI,
+        // which ends up in `schedule`
+        if wake_on(ctx, self.thread) && self.hart != hart::current().id() {
+            let mask = sbi::HartMask {
+                base: self.hart,
+                mask: 1,
+            };
+            let _ = sbi::ipi::send_ipi(mask);
+        }
+    }
+}
+
+/// Mark the current thread as blocked, and return a [`Waker`] that unblocks it again.
+///
+/// The thread keeps running until it calls [`yield_now`], which only switches it out if
+/// the waker wasn't used yet.
+pub fn block_current() -> Waker {
+    with_scheduler(|sched| {
+        let current = sched
+            .current
+            .as_mut()
+            .expect("scheduler not yet initialized");
+        current.blocked = true;
+
+        Waker {
+            hart: hart::current().id(),
+            thread: current.shared.id,
+        }
+    })
+}
+
+/// Put the current thread to sleep for at least the given duration.
+///
+/// Interrupts must be enabled, otherwise the thread can't be switched out.
+pub fn sleep(duration: Duration) {
+    block_for(duration);
+
+    // raise a software interrupt on this hart, which will end up in `schedule`
+    unsafe { csr::sip::set(SIP_SSIP) };
+}
+
+/// Put the current thread to sleep from inside a trap.
+///
+/// The thread is switched out at the end of the trap.
+pub(crate) fn sleep_current(duration: Duration) {
+    block_for(duration);
+}
+
+/// Return the process of the thread that is currently running.
+pub(crate) fn current_process() -> Option<Arc<Process>> {
+    with_scheduler(|sched| sched.current.as_ref()?.process.clone())
+}
+
+/// Return the id of the thread that is currently running.
+pub fn current() -> ThreadId {
+    with_scheduler(|sched| {
+        sched
+            .current
+            .as_ref()
+            .expect("scheduler not yet initialized")
+            .shared
+            .id
+    })
+}
+
+/// Exit the current thread with the given exit code.
+///
+/// # Panics
+///
+/// Panics if this is the thread that booted the hart.
+pub fn exit(code: usize) -> ! {
+    with_scheduler(|sched| {
+        let current = sched
+            .current
+            .as_ref()
+            .expect("scheduler not yet initialized");
+        assert!(
+            current.stack.is_some(),
+            "the boot thread of a hart can't exit"
+        );
+
+        current.shared.exit_code.store(code, Ordering::Relaxed);
+        current.shared.exited.store(true, Ordering::Release);
+    });
+
+    // interrupts must be enabled, otherwise we would never be switched out
+    yield_now();
+    unsafe { csr::sstatus::set(SSTATUS_SIE) };
+
+    loop {
+        riscv::asm::wfi();
+    }
+}
+
+/// Kill the current thread from inside a trap.
+///
+/// The thread is switched out at the end of the trap, and will never run again.
+pub(crate) fn kill_current(code: usize) {
+    with_scheduler(|sched| {
+        let current = sched
+            .current
+            .as_ref()
+            .expect("scheduler not yet initialized");
+
+        current.shared.exit_code.store(code, Ordering::Relaxed);
+        current.shared.exited.store(true, Ordering::Release);
+        sched.need_resched = true;
+    });
+}
+
+/// The idle thread of every hart, which waits for interrupts.
+extern "C" fn idle_entry() -> ! {
+    loop {
+        riscv::asm::wfi();
+    }
+}
+
+/// The first code that runs inside a spawned thread.
+extern "C" fn thread_entry(main: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    exit(0)
+}
+
+/// Spawn a new thread on the current hart, that will run `f`.
+pub fn spawn<F>(f: F) -> Result<JoinHandle, page::Error>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_task(None, f)
+}
+
+/// Spawn a new thread on the current hart, that belongs to the given process and will run `f`.
+pub(crate) fn spawn_in<F>(process: Arc<Process>, f: F) -> Result<JoinHandle, page::Error>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_task(Some(process), f)
+}
+
+fn spawn_task<F>(process: Option<Arc<Process>>, f: F) -> Result<JoinHandle, page::Error>
+where
+    F: FnOnce() + Send + 'static,
+{
+    reap();
+
+    // the closure is boxed twice, so it can be passed as a thin pointer inside a register
+    let main: Box<Box<dyn FnOnce() + Send>> = Box::new(Box::new(f));
+    let main = Box::into_raw(main);
+
+    let task = match Task::new(process, thread_entry as usize, main as usize) {
+        Ok(task) => Box::new(task),
+        Err(err) => {
+            drop(unsafe { Box::from_raw(main) });
+            return Err(err);
+        }
+    };
+    let shared = Arc::clone(&task.shared);
+
+    with_scheduler(|sched| {
+        sched.ready.push_back(task);
+
+        // make sure there's enough space to store every thread if it exits,
+        // so `schedule` never has to allocate
+        let threads = sched.ready.len() + 1;
+        sched.dead.reserve(threads);
+    });
+
+    Ok(JoinHandle { shared })
+}
+
+/// A handle to a spawned thread, that can be used to wait for it to exit.
+pub struct JoinHandle {
+    shared: Arc<Shared>,
+}
+
+impl JoinHandle {
+    /// Return the id of the thread.
+    pub fn id(&self) -> ThreadId {
+        self.shared.id
+    }
+
+    /// Check if the thread exited already.
+    pub fn is_finished(&self) -> bool {
+        self.shared.exited.load(Ordering::Acquire)
+    }
+
+    /// Wait until the thread exited, and return its exit code.
+    pub fn join(self) -> usize {
+        while !self.is_finished() {
+            yield_now();
+        }
+
+        reap();
+        self.shared.exit_code.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,215 @@
+//! Kernel time keeping and per-hart timers.
+//!
+//! Every hart has its own queue of timer deadlines. The SBI timer of a hart is always
+//! programmed to fire at the earliest deadline inside its queue, and the callbacks of all
+//! expired timers are run from inside the timer interrupt.
+
+use crate::hart;
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use devicetree::DeviceTree;
+
+/// Counter to generate unique timer ids.
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Read the timebase frequency from the `/cpus` node and use it for all time calculations.
+pub fn init(fdt: &DeviceTree<'_>) {
+    let freq = fdt
+        .cpus()
+        .prop("timebase-frequency")
+        .and_then(|prop| prop.as_u32());
+
+    match freq {
+        Some(freq) => riscv::asm::set_timebase_frequency(freq as u64),
+        None => log::warn!(
+            "{} to find timebase frequency, using {} Hz",
+            "Failed".yellow(),
+            riscv::asm::timebase_frequency()
+        ),
+    }
+}
+
+/// Return the time since this hart was booted.
+pub fn now() -> Duration {
+    riscv::asm::time()
+}
+
+/// A unique identifier for a timer, which can be used to cancel or rearm the timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: TimerId,
+    /// The absolute deadline in clock ticks.
+    deadline: u64,
+    /// The period in clock ticks, if this is a periodic timer.
+    period: Option<u64>,
+    /// The callback that is run after the deadline expired.
+    ///
+    /// This is `None` while the callback is executed.
+    callback: Option<Box<dyn FnMut() + Send>>,
+}
+
+/// A queue of timers that is sorted by their deadlines.
+pub struct TimerQueue {
+    timers: Vec<Timer>,
+}
+
+impl TimerQueue {
+    /// Create a new, empty timer queue.
+    pub const fn new() -> Self {
+        Self { timers: Vec::new() }
+    }
+
+    /// Insert the timer at the position that keeps the queue sorted.
+    fn insert(&mut self, timer: Timer) {
+        let idx = self
+            .timers
+            .partition_point(|other| other.deadline <= timer.deadline);
+        self.timers.insert(idx, timer);
+    }
+
+    /// Remove the timer with the given id from this queue.
+    fn remove(&mut self, id: TimerId) -> Option<Timer> {
+        let idx = self.timers.iter().position(|timer| timer.id == id)?;
+        Some(self.timers.remove(idx))
+    }
+
+    /// Return the earliest deadline of a timer, that is not running right now.
+    fn next_deadline(&self) -> Option<u64> {
+        self.timers
+            .iter()
+            .find(|timer| timer.callback.is_some())
+            .map(|timer| timer.deadline)
+    }
+
+    /// Program the SBI timer to fire at the earliest deadline.
+    fn reprogram(&self) {
+        // if there is no timer, we set the deadline to the maximum value,
+        // which also clears the pending timer interrupt.
+        let deadline = self.next_deadline().unwrap_or(u64::MAX);
+        if let Err(err) = sbi::timer::set_timer(deadline) {
+            log::warn!("{} to program timer: {:?}", "Failed".yellow(), err);
+        }
+    }
+}
+
+impl Default for TimerQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `f` with the timer queue of this hart, while interrupts are disabled.
+///
+/// The queue lock disables interrupts, which prevents the timer interrupt from trying
+/// to take the lock while it's held by the interrupted code.
+fn with_queue<R>(f: impl FnOnce(&mut TimerQueue) -> R) -> R {
+    f(&mut hart::current().timers().lock())
+}
+
+fn arm(delay: Duration, period: Option<Duration>, callback: Box<dyn FnMut() + Send>) -> TimerId {
+    let id = TimerId(NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+    let timer = Timer {
+        id,
+        deadline: (riscv::asm::rdtime() as u64)
+            .saturating_add(riscv::asm::duration_to_ticks(delay)),
+        period: period.map(|p| riscv::asm::duration_to_ticks(p).max(1)),
+        callback: Some(callback),
+    };
+
+    with_queue(|queue| {
+        queue.insert(timer);
+        queue.reprogram();
+    });
+
+    id
+}
+
+/// Arm a one-shot timer on the current hart, that will run `callback` after `delay`.
+pub fn after(delay: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    arm(delay, None, Box::new(callback))
+}
+
+/// Arm a periodic timer on the current hart, that will run `callback` every `period`.
+pub fn every(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    arm(period, Some(period), Box::new(callback))
+}
+
+/// Cancel the timer with the given id on the current hart.
+///
+/// Returns `false` if there was no timer with the given id.
+pub fn cancel(id: TimerId) -> bool {
+    with_queue(|queue| {
+        let removed = queue.remove(id).is_some();
+        queue.reprogram();
+        removed
+    })
+}
+
+/// Change the deadline of the timer with the given id, so it expires after `delay`.
+///
+/// Returns `false` if there was no timer with the given id.
+pub fn rearm(id: TimerId, delay: Duration) -> bool {
+    with_queue(|queue| {
+        let mut timer = match queue.remove(id) {
+            Some(timer) => timer,
+            None => return false,
+        };
+
+        timer.deadline =
+            (riscv::asm::rdtime() as u64).saturating_add(riscv::asm::duration_to_ticks(delay));
+        queue.insert(timer);
+        queue.reprogram();
+        true
+    })
+}
+
+/// Run the callbacks of all expired timers on this hart.
+///
+/// This is called from inside the timer interrupt.
+pub(crate) fn handle_interrupt() {
+    let now = riscv::asm::rdtime() as u64;
+
+    loop {
+        // take the callback of the next expired timer out of the queue, so we
+        // can run it without holding the lock
+        let expired = with_queue(|queue| {
+            let timer = queue
+                .timers
+                .iter_mut()
+                .find(|timer| timer.callback.is_some() && timer.deadline <= now)?;
+            Some((timer.id, timer.callback.take()?))
+        });
+
+        let (id, mut callback) = match expired {
+            Some(x) => x,
+            None => break,
+        };
+
+        callback();
+
+        // put the callback back and calculate the next deadline.
+        // if the timer was cancelled while running, it's not in the queue anymore.
+        with_queue(|queue| {
+            let mut timer = match queue.remove(id) {
+                Some(timer) => timer,
+                None => return,
+            };
+            timer.callback = Some(callback);
+
+            if timer.deadline > now {
+                // the timer was rearmed while the callback was running
+                queue.insert(timer);
+            } else if let Some(period) = timer.period {
+                // skip every period we missed
+                let missed = (now - timer.deadline) / period + 1;
+                timer.deadline += missed * period;
+                queue.insert(timer);
+            }
+        });
+    }
+
+    with_queue(|queue| queue.reprogram());
+}
@@ -0,0 +1,339 @@
+//! Invalidation of TLB entries on every hart that may cache them.
+//!
+//! `sfence.vma` only affects the hart that executes it. Changes to a page table are
+//! collected inside a [`Shootdown`], which flushes the local TLB and uses the SBI RFENCE
+//! extension to flush the TLBs of the other harts that use the page table. The SBI call
+//! only returns after the remote harts executed the fence.
+//!
+//! Changes to the kernel page table are sent to every hart, because its entries are shared
+//! with every address space. For any other page table, only the harts that currently run it
+//! are interrupted. Every hart remembers the last few ASIDs it used, because it may still
+//! cache entries that are tagged with them. A shootdown marks the ASID as stale on the harts
+//! that remember it, and they flush it the next time they switch to it, see [`activate`].
+//! A hart that forgets an ASID flushes its entries right away.
+
+use crate::hart::{self, MAX_HARTS};
+use crate::{page, pmem};
+use core::ptr::NonNull;
+use core::sync::atomic::{self, AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+/// The maximum number of ranges a [`Shootdown`] collects, before it flushes
+/// the whole address space instead.
+const MAX_RANGES: usize = 8;
+
+/// The maximum number of pages a [`Shootdown`] frees after the flush, before it has
+/// to flush early.
+const MAX_FREED: usize = 32;
+
+/// Ranges with more pages than this are flushed using a single `sfence.vma`
+/// for the whole address space.
+const MAX_PAGES: usize = 64;
+
+/// The number of ASIDs every hart remembers.
+const SLOTS: usize = 8;
+
+/// The bit inside a slot, that is set if the entries of the ASID must be flushed.
+const STALE: u32 = 1 << 16;
+
+const PAGE_SIZE: usize = 4096;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SATP: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: AtomicU32 = AtomicU32::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOTS: [AtomicU32; SLOTS] = [EMPTY_SLOT; SLOTS];
+#[allow(clippy::declare_interior_mutable_const)]
+const FIRST_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+/// The raw `satp` value of every hart, that was passed to [`activate`].
+static ACTIVE: [AtomicUsize; MAX_HARTS] = [NO_SATP; MAX_HARTS];
+
+/// The ASIDs whose entries may be cached by every hart, together with the [`STALE`] bit.
+///
+/// The kernel ASID `0` is never stored, so `0` marks an empty slot.
+static LOADED: [[AtomicU32; SLOTS]; MAX_HARTS] = [EMPTY_SLOTS; MAX_HARTS];
+
+/// The slot every hart evicts next.
+static NEXT_SLOT: [AtomicUsize; MAX_HARTS] = [FIRST_SLOT; MAX_HARTS];
+
+/// Whether the SBI implementation supports the RFENCE extension.
+static RFENCE: AtomicBool = AtomicBool::new(false);
+
+/// Find out if remote TLB flushes are supported by the SBI implementation.
+pub fn init() {
+    let supported = sbi::base::probe_ext(sbi::rfence::EXTENSION_ID).unwrap_or(false);
+    RFENCE.store(supported, Ordering::Relaxed);
+
+    if !supported {
+        log::warn!(
+            "{} RFENCE extension, TLBs of other harts are not flushed",
+            "Missing".yellow()
+        );
+    }
+}
+
+/// Switch the current hart to the address space described by the raw `satp` value,
+/// and flush its TLB if it may contain stale entries.
+pub unsafe fn activate(satp: usize) {
+    let hart = match hart::try_current() {
+        Some(ctx) => ctx.id() as usize,
+        None => {
+            asm!("csrw satp, {}", in(reg) satp);
+            riscv::asm::sfence(None, None);
+            return;
+        }
+    };
+
+    // a shootdown, that changes the page table after this point, has to see that
+    // this hart uses it, before the hart can cache any of its entries
+    ACTIVE[hart].store(satp, Ordering::SeqCst);
+    let asid = asid(satp);
+    let (stale, evicted) = match asid {
+        0 => (true, None),
+        asid => load(hart, asid),
+    };
+    atomic::fence(Ordering::SeqCst);
+
+    asm!("csrw satp, {}", in(reg) satp);
+
+    // without an ASID, the TLB can't tell the entries of different address spaces apart
+    match asid {
+        0 => riscv::asm::sfence(None, None),
+        asid if stale => riscv::asm::sfence(None, asid),
+        _ => {}
+    }
+
+    // the evicted ASID may have been in use until `satp` was written
+    if let Some(evicted) = evicted {
+        riscv::asm::sfence(None, evicted);
+    }
+}
+
+/// Make sure `asid` is remembered by `hart`.
+///
+/// Returns if its entries are stale, and the ASID that had to be forgotten for it.
+fn load(hart: usize, asid: u16) -> (bool, Option<u16>) {
+    let slots = &LOADED[hart];
+    if let Some(slot) = slots
+        .iter()
+        .find(|slot| slot.load(Ordering::SeqCst) & !STALE == asid as u32)
+    {
+        let stale = slot.swap(asid as u32, Ordering::SeqCst) & STALE != 0;
+        return (stale, None);
+    }
+
+    // the hart never used the ASID since it was last flushed, so it can't be stale
+    let idx = NEXT_SLOT[hart].fetch_add(1, Ordering::Relaxed) % SLOTS;
+    let old = slots[idx].swap(asid as u32, Ordering::SeqCst) & !STALE;
+    (false, Some(old as u16).filter(|&old| old != 0))
+}
+
+/// Flush every entry that is tagged with `asid` on every hart that may cache it.
+pub fn flush_asid(asid: u16) {
+    riscv::asm::sfence(None, asid);
+
+    atomic::fence(Ordering::SeqCst);
+    let remote = remote_harts();
+    let targets = (0..MAX_HARTS)
+        .filter(|&hart| remote & (1 << hart) != 0 && remembers(hart, asid))
+        .fold(0, |mask, hart| mask | 1 << hart);
+    send(targets, 0, usize::MAX, Some(asid));
+}
+
+/// A batch of address ranges inside one address space, whose TLB entries must be flushed,
+/// and of pages that can only be freed after the flush.
+///
+/// Everything that is still collected is flushed when the batch is dropped.
+#[derive(Debug)]
+pub struct Shootdown {
+    satp: usize,
+    ranges: [(usize, usize); MAX_RANGES],
+    len: usize,
+    all: bool,
+    freed: [(usize, usize); MAX_FREED],
+    freed_len: usize,
+}
+
+impl Shootdown {
+    /// Create an empty batch for the page table that is described by the raw `satp` value.
+    pub fn new(satp: usize) -> Self {
+        Self {
+            satp,
+            ranges: [(0, 0); MAX_RANGES],
+            len: 0,
+            all: false,
+            freed: [(0, 0); MAX_FREED],
+            freed_len: 0,
+        }
+    }
+
+    /// Add the range of `size` bytes starting at `start`.
+    pub fn add(&mut self, start: usize, size: usize) {
+        if self.all {
+            return;
+        }
+
+        // ranges are usually added in ascending order, so try to extend the last one
+        let end = start + size;
+        if let Some(last) = self.ranges[..self.len].last_mut() {
+            if last.1 == start {
+                last.1 = end;
+                return;
+            }
+        }
+
+        if self.len == MAX_RANGES {
+            self.all = true;
+        } else {
+            self.ranges[self.len] = (start, end);
+            self.len += 1;
+        }
+    }
+
+    /// Flush the whole address space, instead of single ranges.
+    ///
+    /// This is required after entries that point to other page tables were removed,
+    /// because `sfence.vma` with an address only flushes the cached leaf entries.
+    pub fn add_all(&mut self) {
+        self.all = true;
+    }
+
+    /// Give the physical block of the given order back to the allocator, once every
+    /// collected range was flushed.
+    ///
+    /// This is used for pages and page tables, that may still be accessed through
+    /// stale TLB entries.
+    pub fn free_after(&mut self, block: NonNull<u8>, order: usize) {
+        if self.freed_len == MAX_FREED {
+            self.flush_now();
+        }
+
+        self.freed[self.freed_len] = (block.as_ptr() as usize, order);
+        self.freed_len += 1;
+    }
+
+    /// Check if there's nothing to flush.
+    pub fn is_empty(&self) -> bool {
+        !self.all && self.len == 0
+    }
+
+    /// Flush every collected range on this hart and every other hart that uses the
+    /// address space, wait until they are done, and free the collected pages.
+    pub fn flush(mut self) {
+        self.flush_now();
+    }
+
+    fn flush_now(&mut self) {
+        if !self.is_empty() {
+            self.send();
+        }
+
+        for &(block, order) in &self.freed[..self.freed_len] {
+            let block = NonNull::new(block as *mut u8).unwrap();
+            let _ = unsafe { pmem::free_order(block, order) };
+        }
+
+        self.len = 0;
+        self.all = false;
+        self.freed_len = 0;
+    }
+
+    fn send(&self) {
+        // global kernel entries are not tagged with an ASID
+        let kernel = self.satp == page::kernel_satp();
+        let asid = if kernel { None } else { Some(asid(self.satp)) };
+
+        // flush the local TLB
+        let too_large = |&(start, end): &(usize, usize)| (end - start) / PAGE_SIZE > MAX_PAGES;
+        let ranges = &self.ranges[..self.len];
+        if self.all || ranges.iter().any(too_large) {
+            riscv::asm::sfence(None, asid);
+        } else {
+            for &(start, end) in ranges {
+                for page in (start..end).step_by(PAGE_SIZE) {
+                    riscv::asm::sfence(page, asid);
+                }
+            }
+        }
+
+        // the changes to the page table must be visible, before it's checked
+        // which harts use it
+        atomic::fence(Ordering::SeqCst);
+
+        // find the other harts that may cache the entries
+        let remote = remote_harts();
+        let mut targets = 0u64;
+        for hart in (0..MAX_HARTS).filter(|hart| remote & (1 << hart) != 0) {
+            // harts that don't run the address space right now, flush it
+            // when they switch to it the next time
+            if let Some(asid) = asid.filter(|&asid| asid != 0) {
+                mark_stale(hart, asid);
+            }
+
+            if kernel || ACTIVE[hart].load(Ordering::SeqCst) == self.satp {
+                targets |= 1 << hart;
+            }
+        }
+
+        if self.all {
+            send(targets, 0, usize::MAX, asid);
+        } else {
+            for &(start, end) in ranges {
+                send(targets, start, end - start, asid);
+            }
+        }
+    }
+}
+
+impl Drop for Shootdown {
+    fn drop(&mut self) {
+        self.flush_now();
+    }
+}
+
+/// Return the ASID of a raw `satp` value.
+fn asid(satp: usize) -> u16 {
+    ((satp >> 44) & 0xFFFF) as u16
+}
+
+/// Check if `hart` may cache entries of `asid`.
+fn remembers(hart: usize, asid: u16) -> bool {
+    LOADED[hart]
+        .iter()
+        .any(|slot| slot.load(Ordering::SeqCst) & !STALE == asid as u32)
+}
+
+/// Set the [`STALE`] bit of `asid` on `hart`, if the hart remembers it.
+fn mark_stale(hart: usize, asid: u16) {
+    for slot in &LOADED[hart] {
+        let _ = slot.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |val| {
+            (val & !STALE == asid as u32).then(|| val | STALE)
+        });
+    }
+}
+
+/// Return the mask of every online hart, except the current one.
+fn remote_harts() -> u64 {
+    let current = hart::try_current().map(|ctx| ctx.id());
+    (0..MAX_HARTS as u64)
+        .filter(|&hart| Some(hart) != current && hart::is_online(hart))
+        .fold(0, |mask, hart| mask | 1 << hart)
+}
+
+/// Tell the harts inside `mask` to flush the range of `size` bytes starting at `start`.
+fn send(mask: u64, start: usize, size: usize, asid: Option<u16>) {
+    if mask == 0 || !RFENCE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mask = sbi::HartMask { base: 0, mask };
+    let res = match asid {
+        Some(asid) => sbi::rfence::sfence_vma_asid(mask, start, size, asid),
+        None => sbi::rfence::sfence_vma(mask, start, size),
+    };
+
+    if let Err(err) = res {
+        log::warn!("{} to flush remote TLBs: {:?}", "Failed".yellow(), err);
+    }
+}
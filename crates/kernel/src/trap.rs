@@ -1,7 +1,34 @@
 //! Trap handler
 
+mod report;
+pub use report::Report;
+
+use crate::{hart, memmap, process, syscall, thread, time};
+use core::marker::PhantomData;
 use riscv::{csr, trap::Trap};
 
+/// The `SIE` bit inside the `sstatus` CSR.
+pub(crate) const SSTATUS_SIE: usize = 1 << 1;
+
+/// The `SPIE` bit inside the `sstatus` CSR.
+pub(crate) const SSTATUS_SPIE: usize = 1 << 5;
+
+/// The `SPP` bit inside the `sstatus` CSR.
+pub(crate) const SSTATUS_SPP: usize = 1 << 8;
+
+/// The `FS` field inside the `sstatus` CSR, which is zero if the FPU is turned off.
+pub(crate) const SSTATUS_FS: usize = 0b11 << 13;
+
+/// The value of the `FS` field, if the floating point registers were not modified
+/// since they were loaded.
+pub(crate) const SSTATUS_FS_CLEAN: usize = 0b10 << 13;
+
+/// The value of the `FS` field, if the floating point registers were modified.
+pub(crate) const SSTATUS_FS_DIRTY: usize = 0b11 << 13;
+
+/// The `SSIP` bit inside the `sip` CSR.
+pub(crate) const SIP_SSIP: usize = 1 << 1;
+
 /// Installs the global trap handler by writing it's address
 /// into the stvec register.
 ///
@@ -12,62 +39,272 @@ pub fn install_handler() {
         // Tell the CPU that our trap handler is at `addr`
         csr::stvec::write(addr);
 
+        // The kernel doesn't use the FPU, it's only turned on for threads that use it
+        csr::sstatus::clear(SSTATUS_FS);
+
         // Enable external interrupts
         csr::sie::set((1 << 9) | (1 << 5) | (1 << 1));
         csr::sstatus::set(1 << 1);
     }
 }
 
+/// Guard that restores the previous interrupt state of this hart when it's dropped.
+///
+/// The guard must be dropped on the hart it was created on,
+/// which is why it's neither `Send` nor `Sync`.
+#[must_use = "the previous interrupt state is restored as soon as the guard is dropped"]
+pub struct InterruptGuard {
+    enabled: bool,
+    _hart_local: PhantomData<*const ()>,
+}
+
+impl InterruptGuard {
+    fn new(enable: bool) -> Self {
+        let enabled = interrupts_enabled();
+        unsafe {
+            match enable {
+                true => csr::sstatus::set(SSTATUS_SIE),
+                false => csr::sstatus::clear(SSTATUS_SIE),
+            }
+        }
+
+        Self {
+            enabled,
+            _hart_local: PhantomData,
+        }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        unsafe {
+            match self.enabled {
+                true => csr::sstatus::set(SSTATUS_SIE),
+                false => csr::sstatus::clear(SSTATUS_SIE),
+            }
+        }
+    }
+}
+
+/// Check if interrupts are enabled on this hart.
+pub fn interrupts_enabled() -> bool {
+    unsafe { csr::sstatus::read() & SSTATUS_SIE != 0 }
+}
+
+/// Disable interrupts on this hart, until the returned guard is dropped.
+pub fn disable_interrupts() -> InterruptGuard {
+    InterruptGuard::new(false)
+}
+
+/// Enable interrupts on this hart, until the returned guard is dropped.
+///
+/// Trap handlers run with interrupts disabled. A handler that may take a while should
+/// enable them, so other interrupts are handled as nested traps in the meantime.
+/// No lock that is also taken by an interrupt handler must be held while
+/// interrupts are enabled.
+///
+/// Conversely, a lock that doesn't disable interrupts may be held by a thread that was
+/// preempted, so it must only be taken while interrupts are enabled.
+pub fn enable_interrupts() -> InterruptGuard {
+    InterruptGuard::new(true)
+}
+
+/// Run `f` while interrupts are disabled on this hart, and restore
+/// the previous interrupt state afterwards.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = disable_interrupts();
+    f()
+}
+
 /// The rust trap handler
 ///
 /// The returned value will be the new `sepc` value.
+///
+/// The handler is entered with interrupts disabled. A trap that happens while another
+/// trap is handled, is handled on the stack of the interrupted handler, and returns
+/// to the interrupted handler without switching threads.
 pub extern "C" fn trap_handler(
-    _frame: &mut TrapFrame,
+    frame: &mut TrapFrame,
     scause: usize,
     stval: usize,
-    sepc: usize,
+    mut sepc: usize,
 ) -> usize {
     let cause = match Trap::from_cause(scause) {
         Some(x) => x,
         None => panic!(
-            "Invalid trap cause: {:x?} pc: {:#x?} tval: {:#x?}",
-            scause, sepc, stval
+            "Invalid trap cause\n{}",
+            Report {
+                frame,
+                scause,
+                stval,
+                sepc
+            }
         ),
     };
 
+    let ctx = hart::current();
+    let nested = ctx.enter_trap() > 0;
+
+    // the previous privilege mode is stored inside `sstatus.SPP`
+    let from_user = frame.sstatus & SSTATUS_SPP == 0;
+
+    // remember where the kernel was interrupted, so a panic can print a backtrace of it.
+    // a nested trap hides the origin of the outer trap until it returns
+    let outer_origin = ctx.trap_origin();
+    if !from_user {
+        ctx.set_trap_origin(Some((sepc, frame.xregs.s0)));
+    }
+
     match cause {
         Trap::SupervisorExternalInterrupt => {
             // If there is a PLIC, and it has a pending interrupt,
             // we pass it on to our devicemanager which will redirect the interrupt
             // to the corresponding device.
-            //let dev = hart::current().devices();
-            //if let Some(irq) = dev
-            //.plic()
-            //.as_mut()
-            //.and_then(|p| p.claim(hart::current().plic_context()))
-            //{
-            //match hart::current().devices().handle_interrupt(irq) {
-            //Ok(()) => {}
-            //Err(err) => {
-            //log::warn!("{} to run interrupt handler: {}", "Failed".yellow(), err)
-            //}
-            //}
-            //}
-        }
-        Trap::SupervisorTimerInterrupt => {
-            log::debug!("got timer interrupt");
+            let ctx = hart::current();
+            if let Some(dev) = ctx.devices() {
+                if let Some(irq) = dev.plic().and_then(|p| p.claim(ctx.plic_context())) {
+                    if let Err(err) = dev.handle_interrupt(irq.id()) {
+                        log::warn!("{} to run interrupt handler: {}", "Failed".yellow(), err);
+                    }
+
+                    // the interrupt must be completed either way, otherwise the PLIC
+                    // never forwards it again
+                    irq.finish();
+                }
+            }
         }
+        Trap::SupervisorTimerInterrupt => time::handle_interrupt(),
         Trap::SupervisorSoftwareInterrupt => {
-            // this is temporarily a signal to shutdown this hart
-            loop {}
+            unsafe { csr::sip::clear(SIP_SSIP) };
+
+            // the boot hart sends an IPI to stop all other harts if it panics.
+            // otherwise, this is a request to reschedule, which is handled below.
+            if crate::panic::is_panicking() {
+                loop {}
+            }
+        }
+        // the FPU is turned off, until a thread uses it for the first time.
+        // if the instruction is still illegal afterwards, the thread is killed below
+        Trap::IllegalInstruction if from_user && frame.sstatus & SSTATUS_FS == 0 => {
+            thread::enable_fpu(frame)
+        }
+        Trap::UserModeEnvironmentCall => {
+            // system calls may take a while, so they can be interrupted
+            let _irq = enable_interrupts();
+            sepc = syscall::handle(frame, sepc);
+        }
+        // the trap entry already switched to the emergency stack
+        Trap::LoadPageFault | Trap::StorePageFault
+            if !from_user && memmap::is_stack_guard(stval) =>
+        {
+            panic!(
+                "stack overflow on hart {}\n{}",
+                ctx.id(),
+                Report {
+                    frame,
+                    scause,
+                    stval,
+                    sepc
+                }
+            )
+        }
+        Trap::InstructionPageFault | Trap::LoadPageFault | Trap::StorePageFault if from_user => {
+            // the address space may be locked by a preempted thread of the same process,
+            // so the fault is handled with interrupts enabled, just like a system call
+            let _irq = enable_interrupts();
+
+            // the fault was already reported
+            if process::handle_page_fault(cause, stval, sepc).is_err() {
+                thread::kill_current(usize::MAX);
+            }
+        }
+        // user code can't take down the kernel, so we only kill the faulting thread
+        trap if from_user => {
+            log::warn!(
+                "{} thread after {:?} in user mode pc: {:#x?} tval: {:#x?}",
+                "Killing".yellow(),
+                trap,
+                sepc,
+                stval
+            );
+            thread::kill_current(usize::MAX);
         }
         trap => panic!(
-            "Unhandled trap: {:?} pc: {:#x?} tval: {:#x?}",
-            trap, sepc, stval
+            "Unhandled trap: {:?}\n{}",
+            trap,
+            Report {
+                frame,
+                scause,
+                stval,
+                sepc
+            }
         ),
     };
 
-    sepc
+    ctx.set_trap_origin(outer_origin);
+    ctx.leave_trap();
+
+    // a nested trap interrupted the handler of another trap, which has to finish first
+    if nested {
+        return sepc;
+    }
+
+    // switch to the next thread, if the current one gave up the hart
+    // or used up its time slice
+    thread::schedule(frame, sepc)
+}
+
+/// Load the given registers into the FPU.
+///
+/// The FPU is turned off again afterwards, so the kernel can't modify the registers.
+pub(crate) unsafe fn load_fregs(regs: &FRegisters) {
+    csr::sstatus::set(SSTATUS_FS_CLEAN);
+
+    // the kernel never keeps values inside floating point registers,
+    // so they don't have to be marked as clobbered
+    asm!(
+        "
+        fld f0, 0({regs})
+        fld f1, 8({regs})
+        fld f2, 16({regs})
+        fld f3, 24({regs})
+        fld f4, 32({regs})
+        fld f5, 40({regs})
+        fld f6, 48({regs})
+        fld f7, 56({regs})
+        fld f8, 64({regs})
+        fld f9, 72({regs})
+        fld f10, 80({regs})
+        fld f11, 88({regs})
+        fld f12, 96({regs})
+        fld f13, 104({regs})
+        fld f14, 112({regs})
+        fld f15, 120({regs})
+        fld f16, 128({regs})
+        fld f17, 136({regs})
+        fld f18, 144({regs})
+        fld f19, 152({regs})
+        fld f20, 160({regs})
+        fld f21, 168({regs})
+        fld f22, 176({regs})
+        fld f23, 184({regs})
+        fld f24, 192({regs})
+        fld f25, 200({regs})
+        fld f26, 208({regs})
+        fld f27, 216({regs})
+        fld f28, 224({regs})
+        fld f29, 232({regs})
+        fld f30, 240({regs})
+        fld f31, 248({regs})
+        ld {tmp}, 256({regs})
+        fscsr {tmp}
+        ",
+        regs = in(reg) regs,
+        tmp = out(reg) _,
+        options(nostack)
+    );
+
+    csr::sstatus::clear(SSTATUS_FS);
 }
 
 /// The global trap handler that will save the registers and then
@@ -77,24 +314,87 @@ pub extern "C" fn trap_handler(
 unsafe extern "C" fn _trap_handler() -> ! {
     asm!(
         "
-        // We don't want nested interrupts, so disable them
-        csrci sstatus, 2
+        // Interrupts are disabled by the CPU while entering the trap handler.
+        // Swap in the hart context, to get two registers to work with
+        csrrw sp, sscratch, sp
+        sd t0, 16(sp)
+        sd t1, 24(sp)
 
-        csrrw s0, sscratch, s0
+        // Find the stack to use, and store its end inside t0
+        csrr t0, sstatus
+        andi t0, t0, 1 << 8
+        bnez t0, 1f
 
-        // Load the interrupt stack
-        sd sp, 16(s0)
-        ld sp, 8(s0)
-        addi sp, sp, -512
+        // A trap from user mode starts at the top of the trap stack
+        ld t0, 8(sp)
+        j 3f
 
-        // Store the registers inside the trap frame
-        sd x1, 0(sp)
+    1:
+        // A page fault inside the guard page of a kernel stack means the kernel overflowed
+        // its stack, so the emergency stack is used
+        csrr t0, scause
+        li t1, 13
+        beq t0, t1, 2f
+        li t1, 15
+        bne t0, t1, 4f
+    2:
+        // Find the stack region that contains the address. Afterwards, t0 is the offset
+        // into the region, and t1 the size of a stack slot, which starts with the guard page
+        csrr t0, stval
+        lla t1, {layout}
+        ld t1, 32(t1)
+        bgeu t0, t1, 4f
 
-        // Store the old sp
-        ld x1, 16(s0)
-        sd x1, 8(sp)
+        lla t1, {layout}
+        ld t1, 24(t1)
+        bltu t0, t1, 6f
+        sub t0, t0, t1
+        li t1, {thread_slot}
+        j 9f
+    6:
+        lla t1, {layout}
+        ld t1, 16(t1)
+        bltu t0, t1, 7f
+        sub t0, t0, t1
+        li t1, {emergency_slot}
+        j 9f
+    7:
+        lla t1, {layout}
+        ld t1, 8(t1)
+        bltu t0, t1, 8f
+        sub t0, t0, t1
+        li t1, {trap_slot}
+        j 9f
+    8:
+        lla t1, {layout}
+        ld t1, 0(t1)
+        bltu t0, t1, 4f
+        sub t0, t0, t1
+        li t1, {kernel_slot}
+    9:
+        remu t0, t0, t1
+        li t1, {guard_size}
+        bgeu t0, t1, 4f
+        ld t0, 40(sp)
+        j 3f
 
-        csrrw s0, sscratch, s0
+    4:
+        // Any other trap from supervisor mode is nested inside the interrupted code,
+        // so it keeps using the current stack
+        csrr t0, sscratch
+
+    3:
+        // Allocate the trap frame, and restore the hart context
+        addi t0, t0, -{frame_size}
+        csrrw sp, sscratch, sp
+
+        // Store the old sp and ra, and restore t0 and t1
+        sd sp, 8(t0)
+        mv sp, t0
+        sd x1, 0(sp)
+        csrr x1, sscratch
+        ld t0, 16(x1)
+        ld t1, 24(x1)
 
         sd x3, 16(sp)
         sd x4, 24(sp)
@@ -126,7 +426,17 @@ unsafe extern "C" fn _trap_handler() -> ! {
         sd x30, 232(sp)
         sd x31, 240(sp)
 
-        // Floating point registers
+        // A nested trap overwrites sstatus, so it's restored before returning
+        csrr t1, sstatus
+        sd t1, 512(sp)
+
+        // Only save the floating point registers if they were modified (FS = Dirty),
+        // because the kernel doesn't touch them
+        srli t0, t1, 13
+        andi t0, t0, 3
+        li t2, 3
+        bne t0, t2, 5f
+
         fsd f0, 248(sp)
         fsd f1, 256(sp)
         fsd f2, 264(sp)
@@ -159,13 +469,25 @@ unsafe extern "C" fn _trap_handler() -> ! {
         fsd f29, 480(sp)
         fsd f30, 488(sp)
         fsd f31, 496(sp)
-
         frcsr t0
         sd t0, 504(sp)
 
-        // Re-enable interrupts after we jumped backed to user code
-        li t0, 1 << 5
-        csrs sstatus, t0
+    5:
+        // The kernel runs with the FPU turned off (FS = Off)
+        li t0, 3 << 13
+        csrc sstatus, t0
+
+        // If we trapped from user mode, we need the global and thread pointer of the kernel
+        andi t0, t1, 1 << 8
+        bnez t0, 1f
+
+    .option push
+    .option norelax
+        lla gp, __global_pointer$
+    .option pop
+        csrr t0, sscratch
+        ld tp, 32(t0)
+    1:
 
         // Prepare arguments for jump into Rust code
         mv a0, sp
@@ -174,12 +496,19 @@ unsafe extern "C" fn _trap_handler() -> ! {
         csrr a3, sepc
 
         // Call rust handler
-        call {}
+        call {handler}
         csrw sepc, a0
 
+        // Restore the previous privilege mode, interrupt and FPU state,
+        // which may have been changed by a thread switch. The floating point
+        // registers are never restored here, they are loaded lazily by `enable_fpu`
+        ld t0, 512(sp)
+        li t1, (1 << 8) | (1 << 5) | (3 << 13)
+        and t0, t0, t1
+        csrc sstatus, t1
+        csrs sstatus, t0
+
         // Restore registers from trap frame
-        ld t0, 504(sp)
-        fscsr t0
 
         ld x1, 0(sp)
         ld x3, 16(sp)
@@ -212,46 +541,20 @@ unsafe extern "C" fn _trap_handler() -> ! {
         ld x30, 232(sp)
         ld x31, 240(sp)
 
-        // Floating point registers
-        fld f0, 248(sp)
-        fld f1, 256(sp)
-        fld f2, 264(sp)
-        fld f3, 272(sp)
-        fld f4, 280(sp)
-        fld f5, 288(sp)
-        fld f6, 296(sp)
-        fld f7, 304(sp)
-        fld f8, 312(sp)
-        fld f9, 320(sp)
-        fld f10, 328(sp)
-        fld f11, 336(sp)
-        fld f12, 344(sp)
-        fld f13, 352(sp)
-        fld f14, 360(sp)
-        fld f15, 368(sp)
-        fld f16, 376(sp)
-        fld f17, 384(sp)
-        fld f18, 392(sp)
-        fld f19, 400(sp)
-        fld f20, 408(sp)
-        fld f21, 416(sp)
-        fld f22, 424(sp)
-        fld f23, 432(sp)
-        fld f24, 440(sp)
-        fld f25, 448(sp)
-        fld f26, 456(sp)
-        fld f27, 464(sp)
-        fld f28, 472(sp)
-        fld f29, 480(sp)
-        fld f30, 488(sp)
-        fld f31, 496(sp)
-
         // Restore stack pointer
         ld sp, 8(sp)
 
         // Jump out of the interrupt handler
         sret
-    ", sym trap_handler,
+    ",
+        handler = sym trap_handler,
+        frame_size = const core::mem::size_of::<TrapFrame>(),
+        layout = sym memmap::LAYOUT,
+        kernel_slot = const memmap::KERNEL_STACK_SIZE + memmap::STACK_GUARD_SIZE,
+        trap_slot = const memmap::TRAP_STACK_SIZE + memmap::STACK_GUARD_SIZE,
+        emergency_slot = const memmap::EMERGENCY_STACK_SIZE + memmap::STACK_GUARD_SIZE,
+        thread_slot = const memmap::THREAD_STACK_SIZE + memmap::STACK_GUARD_SIZE,
+        guard_size = const memmap::STACK_GUARD_SIZE,
         options(noreturn)
     )
 }
@@ -259,12 +562,22 @@ unsafe extern "C" fn _trap_handler() -> ! {
 /// The trap frame contains all registers that were stored prior to executing
 /// the interrupt handler, and will be loaded again after the interrupt handler.
 #[repr(C)]
+#[derive(Clone, Default)]
 pub struct TrapFrame {
     pub xregs: XRegisters,
+    /// The floating point registers, which are only saved if they were
+    /// modified by the interrupted code.
     pub fregs: FRegisters,
+    /// The `sstatus` CSR at the time of the trap.
+    ///
+    /// Only `SPP` and `SPIE` are restored when returning from the trap.
+    pub sstatus: usize,
+    /// Keeps the size of the frame a multiple of 16, so the stack stays aligned.
+    _pad: usize,
 }
 
 #[repr(C)]
+#[derive(Clone, Default)]
 pub struct XRegisters {
     pub ra: usize,
     pub sp: usize,
@@ -300,6 +613,7 @@ pub struct XRegisters {
 }
 
 #[repr(C)]
+#[derive(Clone, Default)]
 pub struct FRegisters {
     pub f0: usize,
     pub f1: usize,
@@ -0,0 +1,147 @@
+//! Human readable report of a trap, that is printed if the kernel can't handle a trap.
+
+use super::{TrapFrame, SSTATUS_FS, SSTATUS_SPIE, SSTATUS_SPP};
+use crate::{hart, page, symbols};
+use core::fmt;
+use riscv::{
+    csr,
+    trap::{Trap, INTERRUPT_BIT},
+};
+
+/// The `SUM` bit inside the `sstatus` CSR.
+const SSTATUS_SUM: usize = 1 << 18;
+
+/// Display helper that prints the cause, the CSRs and every register of a trap.
+pub struct Report<'frame> {
+    pub frame: &'frame TrapFrame,
+    pub scause: usize,
+    pub stval: usize,
+    pub sepc: usize,
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match hart::try_current() {
+            Some(ctx) => writeln!(f, "hart:    {}", ctx.id())?,
+            None => writeln!(f, "hart:    <unknown>")?,
+        }
+
+        let trap = Trap::from_cause(self.scause);
+        write!(
+            f,
+            "scause:  {:#018x} ({} {}: ",
+            self.scause,
+            if self.scause & INTERRUPT_BIT != 0 {
+                "interrupt"
+            } else {
+                "exception"
+            },
+            self.scause & !INTERRUPT_BIT,
+        )?;
+        match trap {
+            Some(trap) => writeln!(f, "{:?})", trap)?,
+            None => writeln!(f, "invalid cause)")?,
+        }
+        writeln!(
+            f,
+            "sepc:    {:#018x} ({})",
+            self.sepc,
+            symbols::Display(self.sepc)
+        )?;
+        writeln!(f, "stval:   {:#018x}", self.stval)?;
+
+        let sstatus = self.frame.sstatus;
+        let fs = match (sstatus & SSTATUS_FS) >> SSTATUS_FS.trailing_zeros() {
+            0 => "Off",
+            1 => "Initial",
+            2 => "Clean",
+            _ => "Dirty",
+        };
+        writeln!(
+            f,
+            "sstatus: {:#018x} (SPP: {}, SPIE: {}, SUM: {}, FS: {})",
+            sstatus,
+            if sstatus & SSTATUS_SPP != 0 { "S" } else { "U" },
+            (sstatus & SSTATUS_SPIE != 0) as u8,
+            (sstatus & SSTATUS_SUM != 0) as u8,
+            fs,
+        )?;
+
+        writeln!(f, "registers:")?;
+        let x = &self.frame.xregs;
+        let regs = [
+            ("ra", x.ra),
+            ("sp", x.sp),
+            ("gp", x.gp),
+            ("tp", x.tp),
+            ("t0", x.t0),
+            ("t1", x.t1),
+            ("t2", x.t2),
+            ("s0", x.s0),
+            ("s1", x.s1),
+            ("a0", x.a0),
+            ("a1", x.a1),
+            ("a2", x.a2),
+            ("a3", x.a3),
+            ("a4", x.a4),
+            ("a5", x.a5),
+            ("a6", x.a6),
+            ("a7", x.a7),
+            ("s2", x.s2),
+            ("s3", x.s3),
+            ("s4", x.s4),
+            ("s5", x.s5),
+            ("s6", x.s6),
+            ("s7", x.s7),
+            ("s8", x.s8),
+            ("s9", x.s9),
+            ("s10", x.s10),
+            ("s11", x.s11),
+            ("t3", x.t3),
+            ("t4", x.t4),
+            ("t5", x.t5),
+            ("t6", x.t6),
+        ];
+
+        // four registers per line
+        for line in regs.chunks(4) {
+            f.write_str(" ")?;
+            for (name, val) in line {
+                write!(f, " {:>3}: {:#018x}", name, val)?;
+            }
+            writeln!(f)?;
+        }
+
+        if matches!(
+            trap,
+            Some(Trap::InstructionPageFault | Trap::LoadPageFault | Trap::StorePageFault)
+        ) {
+            writeln!(f, "page table walk for {:#x}:", self.stval)?;
+            let satp = unsafe { csr::satp::read() };
+            for step in unsafe { page::walk(&satp, self.stval.into()) } {
+                let entry = &step.entry;
+                write!(
+                    f,
+                    "  [{}] {:>3}: {:#018x}",
+                    match step.size {
+                        page::PageSize::Kilopage => 'K',
+                        page::PageSize::Megapage => 'M',
+                        page::PageSize::Gigapage => 'G',
+                        page::PageSize::Terapage => 'T',
+                        page::PageSize::Petapage => 'P',
+                    },
+                    step.index,
+                    entry.bits(),
+                )?;
+
+                match (entry.is_valid(), entry.is_leaf()) {
+                    (false, _) => writeln!(f, " invalid")?,
+                    (true, true) => writeln!(f, " page {:#p} | {}", entry.addr(), entry.flags())?,
+                    (true, false) => writeln!(f, " table {:#p}", entry.addr())?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
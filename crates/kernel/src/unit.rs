@@ -28,16 +28,17 @@ where
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let this = Into::<usize>::into(self.0);
-        let count = this as f32;
 
-        match this {
-            0..KIB => write!(f, "{:>6} B", this)?,
-            KIB..MIB => write!(f, "{:>6.2} KiB", count / KIB as f32)?,
-            MIB..GIB => write!(f, "{:>6.2} MiB", count / MIB as f32)?,
-            GIB..TIB => write!(f, "{:>6.2} GiB", count / GIB as f32)?,
-            _ => write!(f, "{:>6.2} TiB", count / TIB as f32)?,
+        // the kernel runs with the FPU turned off, so the fraction is calculated using integers
+        let (unit, name) = match this {
+            0..KIB => return write!(f, "{:>6} B", this),
+            KIB..MIB => (KIB, "KiB"),
+            MIB..GIB => (MIB, "MiB"),
+            GIB..TIB => (GIB, "GiB"),
+            _ => (TIB, "TiB"),
         };
 
-        Ok(())
+        let frac = (this % unit) * 100 / unit;
+        write!(f, "{:>3}.{:02} {}", this / unit, frac, name)
     }
 }
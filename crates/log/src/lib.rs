@@ -19,12 +19,12 @@ use core::marker::PhantomData;
 use core::time::Duration;
 use owo_colors::{colors, Color, OwoColorize};
 
-use riscv::sync::Mutex;
+use riscv::sync::IrqMutex;
 
 const LOGGER_SIZE: usize = 8;
-static LOG: GlobalLogger = GlobalLogger(UnsafeCell::new(Mutex::new(None)));
+static LOG: GlobalLogger = GlobalLogger(UnsafeCell::new(IrqMutex::new(None)));
 
-struct GlobalLogger(UnsafeCell<Mutex<Option<Value<dyn Logger, { LOGGER_SIZE }>>>>);
+struct GlobalLogger(UnsafeCell<IrqMutex<Option<Value<dyn Logger, { LOGGER_SIZE }>>>>);
 
 unsafe impl Send for GlobalLogger {}
 unsafe impl Sync for GlobalLogger {}
@@ -46,6 +46,12 @@ impl<T: Logger + ?Sized> Logger for Box<T> {
     }
 }
 
+impl<T: Logger + ?Sized> Logger for &T {
+    fn write_str(&self, x: &str) -> fmt::Result {
+        (&**self).write_str(x)
+    }
+}
+
 /// Represents any level of a log message.
 pub trait Level {
     type Color: Color;
@@ -153,6 +159,17 @@ pub fn log<L: Level>(module: &str, args: fmt::Arguments<'_>) {
     }
 }
 
+/// Write the given string to the global logger, without any prefix or trailing newline.
+///
+/// Does nothing if there's no global logger.
+pub fn write(s: &str) -> fmt::Result {
+    let mut lock = unsafe { LOG.0.get().as_ref().unwrap() }.lock();
+    match &mut *lock {
+        Some(log) => log.write_str(s),
+        None => Ok(()),
+    }
+}
+
 /// Initializes the global logger.
 ///
 /// Returns `Ok` on success, and `Err` with the given logger if the logger was already initialized,
@@ -168,7 +185,7 @@ pub fn init_log<L: Logger + 'static>(log: L) -> Result<(), L> {
 /// Overwrites the global logger without acquiring the lock or other safety checks.
 pub unsafe fn override_log<L: Logger + 'static>(log: L) -> Result<(), L> {
     let val = Value::<dyn Logger, { LOGGER_SIZE }>::new(log)?;
-    let val = Mutex::new(Some(val));
+    let val = IrqMutex::new(Some(val));
     LOG.0.get().write(val);
     Ok(())
 }
@@ -1,7 +1,13 @@
 //! Safe wrappers around some assembly instructions.
 
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::time::Duration;
 
+/// The frequency of the real time clock in Hz.
+///
+/// Defaults to 10 MHz, which is the frequency used by QEMU.
+static TIMEBASE_FREQUENCY: AtomicU64 = AtomicU64::new(10_000_000);
+
 /// Wrapper around the `wfi` instruction.
 #[inline]
 pub fn wfi() {
@@ -45,8 +51,35 @@ pub fn sfence(addr: impl Into<Option<usize>>, asid: impl Into<Option<u16>>) {
     }
 }
 
+/// Set the frequency of the real time clock, that is used to convert ticks into
+/// a [`Duration`].
+pub fn set_timebase_frequency(hz: u64) {
+    assert_ne!(hz, 0, "timebase frequency must not be zero");
+    TIMEBASE_FREQUENCY.store(hz, Ordering::Relaxed);
+}
+
+/// Return the frequency of the real time clock in Hz.
+pub fn timebase_frequency() -> u64 {
+    TIMEBASE_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Convert a number of real time clock ticks into a [`Duration`].
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let freq = timebase_frequency();
+    let secs = ticks / freq;
+    let nanos = (ticks % freq) * 1_000_000_000 / freq;
+    Duration::new(secs, nanos as u32)
+}
+
+/// Convert a [`Duration`] into the number of real time clock ticks.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let freq = timebase_frequency();
+    let secs = duration.as_secs().saturating_mul(freq);
+    let nanos = duration.subsec_nanos() as u64 * freq / 1_000_000_000;
+    secs.saturating_add(nanos)
+}
+
 /// Return the uptime of this hart.
 pub fn time() -> Duration {
-    let time = rdtime();
-    Duration::from_nanos(time as u64 * 100)
+    ticks_to_duration(rdtime() as u64)
 }
@@ -9,6 +9,7 @@ pub enum Mode {
     Bare,
     Sv39,
     Sv48,
+    Sv57,
 }
 
 /// An abstraction around the bitfield of the `satp` register.
@@ -28,6 +29,7 @@ impl Satp {
             Mode::Bare => 0,
             Mode::Sv39 => 8,
             Mode::Sv48 => 9,
+            Mode::Sv57 => 10,
         };
 
         bits | (mode << 60)
@@ -42,6 +44,7 @@ pub unsafe fn read() -> Satp {
         0 => Mode::Bare,
         8 => Mode::Sv39,
         9 => Mode::Sv48,
+        10 => Mode::Sv57,
         _ => panic!("unimplemented page table mode"),
     };
 
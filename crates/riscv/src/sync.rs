@@ -1,14 +1,67 @@
 //! Synchronization primitives.
 
+mod irq;
+pub use irq::{IrqMutex, IrqMutexGuard, IrqRwLock, IrqRwLockReadGuard, IrqRwLockWriteGuard};
+
+#[cfg(feature = "lock-stats")]
+pub mod stats;
+
+#[cfg(debug_assertions)]
+pub mod lockdep;
+
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+#[cfg(debug_assertions)]
+use core::{any::type_name, panic::Location};
+
+/// A class of locks, that are treated as a single lock by the lock validator.
+///
+/// Locks that are created without a class are validated on their own. Locks that are created
+/// dynamically, like one lock per process, should share a class, so a wrong lock order is
+/// detected no matter which instances were involved, and a lock that reuses the memory of
+/// a freed lock doesn't inherit its class. Classes are identified by their name.
+#[derive(Clone, Copy)]
+pub struct LockClass {
+    #[cfg_attr(not(debug_assertions), allow(dead_code))]
+    name: &'static str,
+}
 
-/// A type which provides exclusive access to a resource by using a ticket lock.
-// TODO: https://lwn.net/Articles/590243/
+impl LockClass {
+    /// Create a new class with the given name, that is used in lock validator reports.
+    pub const fn new(name: &'static str) -> Self {
+        Self { name }
+    }
+}
+
+/// The bit inside the state of a [`Mutex`] that is set while it's locked.
+const LOCKED: usize = 1;
+
+/// A node of the waiting queue of a [`Mutex`].
+///
+/// Every waiting hart spins on the `ready` flag of its own node, instead of the shared lock
+/// word, so a release only touches the cache line of the next waiter. The node lives on the
+/// stack of the waiting hart while it's inside [`Mutex::lock`], which gives every hart one
+/// node per lock it's waiting for, even if a nested trap or another thread on the same hart
+/// starts waiting for a different lock.
+#[repr(align(8))]
+struct Node {
+    next: AtomicPtr<Node>,
+    ready: AtomicBool,
+}
+
+/// A type which provides exclusive access to a resource by using a queued (MCS) spinlock.
+///
+/// The state consists of the `LOCKED` bit and the address of the last node in the waiting
+/// queue. Only the hart at the head of the queue spins on the state, and the lock is handed
+/// to waiters in FIFO order.
 pub struct Mutex<T: ?Sized> {
-    now_serving: AtomicUsize,
-    next_ticket: AtomicUsize,
+    state: AtomicUsize,
+    #[cfg(feature = "lock-stats")]
+    stats: stats::LockStats,
+    #[cfg(debug_assertions)]
+    class: lockdep::ClassCache,
     data: UnsafeCell<T>,
 }
 
@@ -16,9 +69,24 @@ impl<T> Mutex<T> {
     /// Create a new mutex that holds the given value.
     #[inline]
     pub const fn new(val: T) -> Self {
+        Self::new_inner(val, None)
+    }
+
+    /// Create a new mutex that holds the given value, and belongs to the given class.
+    #[inline]
+    pub const fn with_class(val: T, class: LockClass) -> Self {
+        Self::new_inner(val, Some(class))
+    }
+
+    #[inline]
+    #[cfg_attr(not(debug_assertions), allow(unused_variables))]
+    const fn new_inner(val: T, class: Option<LockClass>) -> Self {
         Self {
-            now_serving: AtomicUsize::new(0),
-            next_ticket: AtomicUsize::new(0),
+            state: AtomicUsize::new(0),
+            #[cfg(feature = "lock-stats")]
+            stats: stats::LockStats::new(),
+            #[cfg(debug_assertions)]
+            class: lockdep::ClassCache::new(class),
             data: UnsafeCell::new(val),
         }
     }
@@ -27,19 +95,135 @@ impl<T> Mutex<T> {
 impl<T: ?Sized> Mutex<T> {
     /// Lock this mutex. If the mutex is already locked, spin until it's available.
     #[inline]
+    #[track_caller]
     pub fn lock(&self) -> MutexGuard<'_, T> {
-        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        #[cfg(debug_assertions)]
+        let class = lockdep::acquire(&self.class, type_name::<T>(), Location::caller(), true);
 
-        while self.now_serving.load(Ordering::Acquire) != ticket {
-            core::hint::spin_loop();
+        let _spins = if self.try_acquire() {
+            0
+        } else {
+            self.lock_slow()
+        };
+
+        #[cfg(feature = "lock-stats")]
+        {
+            self.stats.acquired();
+            self.stats.contended(_spins);
         }
 
         MutexGuard {
-            now_serving: &self.now_serving,
-            data: &self.data,
-            ticket,
+            lock: self,
+            #[cfg(feature = "lock-stats")]
+            since: crate::asm::rdtime(),
+            #[cfg(debug_assertions)]
+            class,
         }
     }
+
+    /// Try to lock this mutex, and return `None` if it's already locked.
+    #[inline]
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if !self.try_acquire() {
+            return None;
+        }
+
+        #[cfg(feature = "lock-stats")]
+        self.stats.acquired();
+
+        Some(MutexGuard {
+            lock: self,
+            #[cfg(feature = "lock-stats")]
+            since: crate::asm::rdtime(),
+            #[cfg(debug_assertions)]
+            class: lockdep::acquire(&self.class, type_name::<T>(), Location::caller(), false),
+        })
+    }
+
+    /// Return the statistics of this lock.
+    #[cfg(feature = "lock-stats")]
+    pub fn stats(&self) -> &stats::LockStats {
+        &self.stats
+    }
+
+    /// Take the lock if it's free and nobody is waiting for it.
+    #[inline]
+    fn try_acquire(&self) -> bool {
+        self.state
+            .compare_exchange(0, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Enqueue this hart and wait until it's the owner of the lock.
+    ///
+    /// Returns the number of spins it took to acquire the lock.
+    #[cold]
+    fn lock_slow(&self) -> usize {
+        let node = Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            ready: AtomicBool::new(false),
+        };
+        let me = &node as *const Node as usize;
+        let mut spins = 0;
+
+        // append our node to the end of the queue
+        let prev = self
+            .state
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |state| {
+                Some((state & LOCKED) | me)
+            })
+            .unwrap_or_else(|state| state)
+            & !LOCKED;
+
+        // wait until our predecessor made us the head of the queue
+        if prev != 0 {
+            let prev = unsafe { &*(prev as *const Node) };
+            prev.next.store(me as *mut Node, Ordering::Release);
+
+            while !node.ready.load(Ordering::Acquire) {
+                core::hint::spin_loop();
+                spins += 1;
+            }
+        }
+
+        // as the head of the queue, wait for the owner to release the lock
+        let last = loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & LOCKED != 0 {
+                core::hint::spin_loop();
+                spins += 1;
+                continue;
+            }
+
+            // if we are the only waiter, the queue is emptied
+            let last = state == me;
+            let new = if last { LOCKED } else { state | LOCKED };
+            if self
+                .state
+                .compare_exchange(state, new, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                break last;
+            }
+        };
+
+        // pass the head of the queue to our successor, which may still be linking itself
+        if !last {
+            let next = loop {
+                let next = node.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break next;
+                }
+                core::hint::spin_loop();
+                spins += 1;
+            };
+
+            unsafe { (*next).ready.store(true, Ordering::Release) };
+        }
+
+        spins
+    }
 }
 
 unsafe impl<T: Send> Sync for Mutex<T> {}
@@ -47,37 +231,55 @@ unsafe impl<T: Send> Send for Mutex<T> {}
 
 /// The guard providing protected access to the data of a `Mutex`.
 pub struct MutexGuard<'lock, T: ?Sized> {
-    now_serving: &'lock AtomicUsize,
-    data: &'lock UnsafeCell<T>,
-    ticket: usize,
+    lock: &'lock Mutex<T>,
+    #[cfg(feature = "lock-stats")]
+    since: usize,
+    #[cfg(debug_assertions)]
+    class: Option<usize>,
 }
 
 impl<T: ?Sized> Deref for MutexGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        unsafe { &*self.data.get() }
+        unsafe { &*self.lock.data.get() }
     }
 }
 
 impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.data.get() }
+        unsafe { &mut *self.lock.data.get() }
     }
 }
 
 impl<T: ?Sized> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
-        self.now_serving.store(self.ticket + 1, Ordering::Release);
+        #[cfg(feature = "lock-stats")]
+        self.lock.stats.released(self.since);
+
+        // the queue stays untouched, the head of it is spinning for this bit
+        self.lock.state.fetch_and(!LOCKED, Ordering::Release);
+
+        #[cfg(debug_assertions)]
+        lockdep::release(self.class);
     }
 }
 
-const READER: usize = 1 << 1;
 const WRITER: usize = 1;
+const WRITER_WAITING: usize = 1 << 1;
+const READER: usize = 1 << 2;
 
 /// A lock that provides data access to either one writer or many readers.
+///
+/// The lock prefers writers: as soon as a writer is waiting, no new readers are admitted,
+/// so a steady stream of readers can't starve a writer. Because of this, a thread must never
+/// take a read lock again while it already holds one.
 pub struct RwLock<T: ?Sized> {
     lock: AtomicUsize,
+    #[cfg(feature = "lock-stats")]
+    stats: stats::LockStats,
+    #[cfg(debug_assertions)]
+    class: lockdep::ClassCache,
     data: UnsafeCell<T>,
 }
 
@@ -89,8 +291,12 @@ unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
 /// When the guard falls out of scope it will decrement the read count,
 /// potentially releasing the lock.
 pub struct RwLockReadGuard<'a, T: ?Sized> {
-    lock: &'a AtomicUsize,
+    inner: &'a RwLock<T>,
     data: &'a T,
+    #[cfg(feature = "lock-stats")]
+    since: usize,
+    #[cfg(debug_assertions)]
+    class: Option<usize>,
 }
 
 /// A guard that provides mutable data access.
@@ -99,14 +305,35 @@ pub struct RwLockReadGuard<'a, T: ?Sized> {
 pub struct RwLockWriteGuard<'a, T: ?Sized> {
     inner: &'a RwLock<T>,
     data: &'a mut T,
+    #[cfg(feature = "lock-stats")]
+    since: usize,
+    #[cfg(debug_assertions)]
+    class: Option<usize>,
 }
 
 impl<T> RwLock<T> {
     /// Creates a new read-write spinlock wrapping the supplied data.
     #[inline]
     pub const fn new(data: T) -> Self {
+        Self::new_inner(data, None)
+    }
+
+    /// Creates a new read-write spinlock wrapping the supplied data, that belongs to
+    /// the given class.
+    #[inline]
+    pub const fn with_class(data: T, class: LockClass) -> Self {
+        Self::new_inner(data, Some(class))
+    }
+
+    #[inline]
+    #[cfg_attr(not(debug_assertions), allow(unused_variables))]
+    const fn new_inner(data: T, class: Option<LockClass>) -> Self {
         RwLock {
             lock: AtomicUsize::new(0),
+            #[cfg(feature = "lock-stats")]
+            stats: stats::LockStats::new(),
+            #[cfg(debug_assertions)]
+            class: lockdep::ClassCache::new(class),
             data: UnsafeCell::new(data),
         }
     }
@@ -115,45 +342,156 @@ impl<T> RwLock<T> {
 impl<T: ?Sized> RwLock<T> {
     /// Locks this rwlock with shared read access, blocking the current thread
     /// until it can be acquired.
+    ///
+    /// Read locks are not re-entrant: if a writer starts waiting between two read locks
+    /// of the same thread, the second one deadlocks. Debug builds report every recursive
+    /// read as recursive locking, even if no writer is waiting.
     #[inline]
+    #[track_caller]
     pub fn read(&self) -> RwLockReadGuard<'_, T> {
-        loop {
-            let value = self.lock.fetch_add(READER, Ordering::Acquire);
+        #[cfg(debug_assertions)]
+        let class = lockdep::acquire(&self.class, type_name::<T>(), Location::caller(), true);
 
-            // check if there's a writer holding this lock
-            if value & WRITER != 0 {
-                // undo the operation
-                self.lock.fetch_sub(READER, Ordering::Release);
-                core::hint::spin_loop();
-            } else {
-                // there's no writer for this lock, so we can safely take it
-                return RwLockReadGuard {
-                    lock: &self.lock,
-                    data: unsafe { &*self.data.get() },
-                };
-            }
+        let mut _spins = 0;
+        while !self.try_acquire_read() {
+            core::hint::spin_loop();
+            _spins += 1;
+        }
+
+        #[cfg(feature = "lock-stats")]
+        {
+            self.stats.acquired();
+            self.stats.contended(_spins);
+        }
+
+        RwLockReadGuard {
+            inner: self,
+            data: unsafe { &*self.data.get() },
+            #[cfg(feature = "lock-stats")]
+            since: crate::asm::rdtime(),
+            #[cfg(debug_assertions)]
+            class,
         }
     }
 
+    /// Try to lock this rwlock with shared read access, and return `None`
+    /// if it's locked by a writer, or a writer is waiting for it.
+    #[inline]
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        if !self.try_acquire_read() {
+            return None;
+        }
+
+        #[cfg(feature = "lock-stats")]
+        self.stats.acquired();
+
+        Some(RwLockReadGuard {
+            inner: self,
+            data: unsafe { &*self.data.get() },
+            #[cfg(feature = "lock-stats")]
+            since: crate::asm::rdtime(),
+            #[cfg(debug_assertions)]
+            class: lockdep::acquire(&self.class, type_name::<T>(), Location::caller(), false),
+        })
+    }
+
     /// Lock this rwlock with exclusive write access, blocking the current
     /// thread until it can be acquired.
     #[inline]
+    #[track_caller]
     pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        let class = lockdep::acquire(&self.class, type_name::<T>(), Location::caller(), true);
+
+        let mut _spins = 0;
+        while !self.try_acquire_write() {
+            // block new readers until we got the lock. acquiring the lock clears the bit,
+            // so other waiting writers set it again
+            let value = self.lock.load(Ordering::Relaxed);
+            if value & WRITER_WAITING == 0 {
+                self.lock.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+
+            core::hint::spin_loop();
+            _spins += 1;
+        }
+
+        #[cfg(feature = "lock-stats")]
+        {
+            self.stats.acquired();
+            self.stats.contended(_spins);
+        }
+
+        RwLockWriteGuard {
+            inner: self,
+            data: unsafe { &mut *self.data.get() },
+            #[cfg(feature = "lock-stats")]
+            since: crate::asm::rdtime(),
+            #[cfg(debug_assertions)]
+            class,
+        }
+    }
+
+    /// Try to lock this rwlock with exclusive write access, and return `None`
+    /// if it's locked by a reader or writer.
+    #[inline]
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        if !self.try_acquire_write() {
+            return None;
+        }
+
+        #[cfg(feature = "lock-stats")]
+        self.stats.acquired();
+
+        Some(RwLockWriteGuard {
+            inner: self,
+            data: unsafe { &mut *self.data.get() },
+            #[cfg(feature = "lock-stats")]
+            since: crate::asm::rdtime(),
+            #[cfg(debug_assertions)]
+            class: lockdep::acquire(&self.class, type_name::<T>(), Location::caller(), false),
+        })
+    }
+
+    /// Return the statistics of this lock.
+    #[cfg(feature = "lock-stats")]
+    pub fn stats(&self) -> &stats::LockStats {
+        &self.stats
+    }
+
+    /// Add a reader if there's no writer holding or waiting for the lock.
+    #[inline]
+    fn try_acquire_read(&self) -> bool {
+        let mut value = self.lock.load(Ordering::Relaxed);
         loop {
-            if self
-                .lock
-                .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
-                .is_ok()
-            {
-                return RwLockWriteGuard {
-                    inner: self,
-                    data: unsafe { &mut *self.data.get() },
-                };
-            } else {
-                core::hint::spin_loop();
+            if value & (WRITER | WRITER_WAITING) != 0 {
+                return false;
+            }
+
+            match self.lock.compare_exchange_weak(
+                value,
+                value + READER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(x) => value = x,
             }
         }
     }
+
+    /// Take the writer bit if there are no readers or writers.
+    #[inline]
+    fn try_acquire_write(&self) -> bool {
+        let value = self.lock.load(Ordering::Relaxed);
+        value & !WRITER_WAITING == 0
+            && self
+                .lock
+                .compare_exchange(value, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
 }
 
 impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
@@ -166,8 +504,14 @@ impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
 
 impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
     fn drop(&mut self) {
+        #[cfg(feature = "lock-stats")]
+        self.inner.stats.released(self.since);
+
         // decrement the reader count
-        self.lock.fetch_sub(READER, Ordering::Release);
+        self.inner.lock.fetch_sub(READER, Ordering::Release);
+
+        #[cfg(debug_assertions)]
+        lockdep::release(self.class);
     }
 }
 
@@ -187,7 +531,13 @@ impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
 
 impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
     fn drop(&mut self) {
+        #[cfg(feature = "lock-stats")]
+        self.inner.stats.released(self.since);
+
         // clear the writer bit of this rwlock
         self.inner.lock.fetch_and(!WRITER, Ordering::Release);
+
+        #[cfg(debug_assertions)]
+        lockdep::release(self.class);
     }
 }
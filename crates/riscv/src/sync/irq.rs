@@ -0,0 +1,319 @@
+//! Locks that disable interrupts while they are held.
+//!
+//! A lock that is taken inside an interrupt handler, must never be held by the interrupted
+//! code, otherwise the hart deadlocks. These locks disable interrupts on the current hart
+//! before they are acquired, and restore the previous interrupt state after they are released.
+//!
+//! Every guard restores the state from before it was created, so guards on the same hart must
+//! be dropped in the reverse order they were created. Otherwise interrupts are enabled while
+//! another guard is still alive. Debug builds check the order, and panic if it's violated.
+
+use super::{LockClass, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+/// The `SIE` bit inside the `sstatus` CSR.
+const SSTATUS_SIE: usize = 1 << 1;
+
+/// Check that the guards of every hart are dropped in the reverse order they were created.
+#[cfg(debug_assertions)]
+mod order {
+    use super::super::lockdep::{self, MAX_HARTS};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NO_GUARDS: AtomicUsize = AtomicUsize::new(0);
+
+    /// The number of guards that are alive on every hart.
+    static DEPTH: [AtomicUsize; MAX_HARTS] = [NO_GUARDS; MAX_HARTS];
+
+    /// Record a new guard, and return its depth, which is `0` if the hart is unknown.
+    pub(super) fn push() -> usize {
+        match lockdep::hart_id() {
+            Some(hart) => DEPTH[hart].fetch_add(1, Ordering::Relaxed) + 1,
+            None => 0,
+        }
+    }
+
+    /// Record that the guard with the given depth is dropped.
+    pub(super) fn pop(depth: usize) {
+        let hart = match lockdep::hart_id() {
+            Some(hart) if depth != 0 => hart,
+            _ => return,
+        };
+
+        let top = DEPTH[hart].fetch_sub(1, Ordering::Relaxed);
+        assert_eq!(
+            top, depth,
+            "IRQ-safe lock guards must be dropped in the reverse order they were created"
+        );
+    }
+}
+
+/// Disable interrupts on this hart, and return if they were enabled before.
+#[inline]
+pub(super) fn disable_interrupts() -> bool {
+    let sstatus: usize;
+    unsafe { asm!("csrrci {}, sstatus, {}", out(reg) sstatus, const SSTATUS_SIE) };
+    sstatus & SSTATUS_SIE != 0
+}
+
+/// Enable interrupts again, if they were enabled before [`disable_interrupts`].
+#[inline]
+pub(super) fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe { asm!("csrsi sstatus, {}", const SSTATUS_SIE) };
+    }
+}
+
+/// A [`Mutex`] that disables interrupts while it's locked.
+pub struct IrqMutex<T: ?Sized> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    /// Create a new mutex that holds the given value.
+    #[inline]
+    pub const fn new(val: T) -> Self {
+        Self {
+            inner: Mutex::new(val),
+        }
+    }
+
+    /// Create a new mutex that holds the given value, and belongs to the given class.
+    #[inline]
+    pub const fn with_class(val: T, class: LockClass) -> Self {
+        Self {
+            inner: Mutex::with_class(val, class),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqMutex<T> {
+    /// Disable interrupts and lock this mutex. If the mutex is already locked,
+    /// spin until it's available.
+    #[inline]
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let enabled = disable_interrupts();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            enabled,
+            #[cfg(debug_assertions)]
+            depth: order::push(),
+        }
+    }
+
+    /// Try to lock this mutex, and return `None` if it's already locked.
+    #[inline]
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let enabled = disable_interrupts();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                enabled,
+                #[cfg(debug_assertions)]
+                depth: order::push(),
+            }),
+            None => {
+                restore_interrupts(enabled);
+                None
+            }
+        }
+    }
+
+    /// Return the statistics of this lock.
+    #[cfg(feature = "lock-stats")]
+    pub fn stats(&self) -> &super::stats::LockStats {
+        self.inner.stats()
+    }
+}
+
+/// The guard providing protected access to the data of an `IrqMutex`.
+pub struct IrqMutexGuard<'lock, T: ?Sized> {
+    guard: ManuallyDrop<MutexGuard<'lock, T>>,
+    enabled: bool,
+    #[cfg(debug_assertions)]
+    depth: usize,
+}
+
+impl<T: ?Sized> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // the lock must be released before an interrupt can try to take it
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        #[cfg(debug_assertions)]
+        order::pop(self.depth);
+        restore_interrupts(self.enabled);
+    }
+}
+
+/// A [`RwLock`] that disables interrupts while it's locked.
+pub struct IrqRwLock<T: ?Sized> {
+    inner: RwLock<T>,
+}
+
+impl<T> IrqRwLock<T> {
+    /// Creates a new read-write spinlock wrapping the supplied data.
+    #[inline]
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: RwLock::new(data),
+        }
+    }
+
+    /// Creates a new read-write spinlock wrapping the supplied data, that belongs to
+    /// the given class.
+    #[inline]
+    pub const fn with_class(data: T, class: LockClass) -> Self {
+        Self {
+            inner: RwLock::with_class(data, class),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqRwLock<T> {
+    /// Disable interrupts and lock this rwlock with shared read access,
+    /// spinning until it can be acquired.
+    #[inline]
+    #[track_caller]
+    pub fn read(&self) -> IrqRwLockReadGuard<'_, T> {
+        let enabled = disable_interrupts();
+        IrqRwLockReadGuard {
+            guard: ManuallyDrop::new(self.inner.read()),
+            enabled,
+            #[cfg(debug_assertions)]
+            depth: order::push(),
+        }
+    }
+
+    /// Try to lock this rwlock with shared read access, and return `None`
+    /// if it's locked by a writer.
+    #[inline]
+    #[track_caller]
+    pub fn try_read(&self) -> Option<IrqRwLockReadGuard<'_, T>> {
+        let enabled = disable_interrupts();
+        match self.inner.try_read() {
+            Some(guard) => Some(IrqRwLockReadGuard {
+                guard: ManuallyDrop::new(guard),
+                enabled,
+                #[cfg(debug_assertions)]
+                depth: order::push(),
+            }),
+            None => {
+                restore_interrupts(enabled);
+                None
+            }
+        }
+    }
+
+    /// Disable interrupts and lock this rwlock with exclusive write access,
+    /// spinning until it can be acquired.
+    #[inline]
+    #[track_caller]
+    pub fn write(&self) -> IrqRwLockWriteGuard<'_, T> {
+        let enabled = disable_interrupts();
+        IrqRwLockWriteGuard {
+            guard: ManuallyDrop::new(self.inner.write()),
+            enabled,
+            #[cfg(debug_assertions)]
+            depth: order::push(),
+        }
+    }
+
+    /// Try to lock this rwlock with exclusive write access, and return `None`
+    /// if it's locked by a reader or writer.
+    #[inline]
+    #[track_caller]
+    pub fn try_write(&self) -> Option<IrqRwLockWriteGuard<'_, T>> {
+        let enabled = disable_interrupts();
+        match self.inner.try_write() {
+            Some(guard) => Some(IrqRwLockWriteGuard {
+                guard: ManuallyDrop::new(guard),
+                enabled,
+                #[cfg(debug_assertions)]
+                depth: order::push(),
+            }),
+            None => {
+                restore_interrupts(enabled);
+                None
+            }
+        }
+    }
+
+    /// Return the statistics of this lock.
+    #[cfg(feature = "lock-stats")]
+    pub fn stats(&self) -> &super::stats::LockStats {
+        self.inner.stats()
+    }
+}
+
+/// A guard that provides immutable data access to an `IrqRwLock`.
+pub struct IrqRwLockReadGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<RwLockReadGuard<'a, T>>,
+    enabled: bool,
+    #[cfg(debug_assertions)]
+    depth: usize,
+}
+
+impl<T: ?Sized> Deref for IrqRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        #[cfg(debug_assertions)]
+        order::pop(self.depth);
+        restore_interrupts(self.enabled);
+    }
+}
+
+/// A guard that provides mutable data access to an `IrqRwLock`.
+pub struct IrqRwLockWriteGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<RwLockWriteGuard<'a, T>>,
+    enabled: bool,
+    #[cfg(debug_assertions)]
+    depth: usize,
+}
+
+impl<T: ?Sized> Deref for IrqRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        #[cfg(debug_assertions)]
+        order::pop(self.depth);
+        restore_interrupts(self.enabled);
+    }
+}
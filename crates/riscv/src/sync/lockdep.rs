@@ -0,0 +1,487 @@
+//! A lightweight lock validator, that is only compiled into debug builds.
+//!
+//! Every lock is its own class, unless it was created with an explicit [`LockClass`], which
+//! is shared by all locks that were created with it. Every hart keeps a stack of the locks
+//! it currently holds, and each time a lock is acquired, an edge from every held class to
+//! the new class is recorded, together with the call site that created it.
+//!
+//! A thread can be preempted while it holds locks, so the scheduler has to move the stack
+//! of held locks into the thread using [`switch`].
+//!
+//! Before a hart starts spinning for a lock, the validator checks if the hart already holds
+//! a lock of the same class, or if the new class already leads to one of the held classes.
+//! In the latter case the locks are taken in the opposite order somewhere else, and the
+//! wrong interleaving would deadlock. Both cases panic with the involved call sites,
+//! instead of hanging.
+//!
+//! The validator only knows on which hart it runs after the kernel called [`set_hart_id`].
+
+use super::LockClass;
+use core::cell::UnsafeCell;
+use core::cmp;
+use core::fmt;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+/// The maximum number of lock classes. Locks that don't fit are not validated.
+pub const MAX_CLASSES: usize = 128;
+
+/// The maximum number of harts that are tracked.
+pub const MAX_HARTS: usize = 64;
+
+/// The maximum number of locks a single hart can hold at the same time.
+pub const MAX_HELD: usize = 16;
+
+/// The class of a lock, that is assigned to it on the first acquisition.
+///
+/// Every lock stores this inline, so the class doesn't have to be looked up every time.
+pub(super) struct ClassCache {
+    explicit: Option<LockClass>,
+    class: AtomicUsize,
+}
+
+impl ClassCache {
+    pub(super) const fn new(explicit: Option<LockClass>) -> Self {
+        Self {
+            explicit,
+            class: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the index of the class of this lock, and register it if necessary.
+    ///
+    /// Locks without an explicit class are identified by the address of their cache, which
+    /// is the address of the lock itself, when they are first acquired.
+    fn get(&self, name: &'static str) -> Option<usize> {
+        match self.class.load(Ordering::Relaxed) {
+            0 => {
+                let key = match self.explicit {
+                    Some(class) => Key {
+                        id: 0,
+                        name: class.name,
+                        explicit: true,
+                    },
+                    None => Key {
+                        id: self as *const Self as usize,
+                        name,
+                        explicit: false,
+                    },
+                };
+
+                let class = lookup(key)?;
+                self.class.store(class + 1, Ordering::Relaxed);
+                Some(class)
+            }
+            class => Some(class - 1),
+        }
+    }
+}
+
+/// The identity of a class.
+#[derive(Clone, Copy)]
+struct Key {
+    /// The address of the [`ClassCache`] of the lock, if it has no explicit class.
+    id: usize,
+    name: &'static str,
+    explicit: bool,
+}
+
+impl Key {
+    const EMPTY: Key = Key {
+        id: 0,
+        name: "",
+        explicit: false,
+    };
+
+    /// Check if both keys identify the same class.
+    fn matches(&self, other: &Key) -> bool {
+        match self.explicit {
+            true => other.explicit && self.name == other.name,
+            false => !other.explicit && self.id == other.id,
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.explicit {
+            true => f.write_str(self.name),
+            false => write!(f, "{} at {:#x}", self.name, self.id),
+        }
+    }
+}
+
+/// The call site that acquired a held lock.
+type Site = &'static Location<'static>;
+
+/// The locks that are held by a hart, or by a thread that is not running.
+#[derive(Clone, Copy)]
+pub struct HeldLocks {
+    depth: usize,
+    locks: [(usize, Option<Site>); MAX_HELD],
+}
+
+impl HeldLocks {
+    /// An empty stack of held locks.
+    pub const fn new() -> Self {
+        Self {
+            depth: 0,
+            locks: [(0, None); MAX_HELD],
+        }
+    }
+}
+
+impl Default for HeldLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wrapper around the held locks of a hart, which are only ever accessed by the hart itself
+/// with interrupts disabled.
+struct HartState(UnsafeCell<HeldLocks>);
+
+unsafe impl Sync for HartState {}
+
+impl HartState {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Self = Self(UnsafeCell::new(HeldLocks::new()));
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_EDGE: AtomicPtr<Location<'static>> = AtomicPtr::new(ptr::null_mut());
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_EDGES: [AtomicPtr<Location<'static>>; MAX_CLASSES] = [NO_EDGE; MAX_CLASSES];
+
+/// Function that returns the id of the current hart.
+static HART_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Cleared after the first report, so the panic handler can take locks again.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// The keys of all classes. Entries below `CLASS_COUNT` are never modified again.
+static CLASSES: ClassTable = ClassTable(UnsafeCell::new([Key::EMPTY; MAX_CLASSES]));
+static CLASS_COUNT: AtomicUsize = AtomicUsize::new(0);
+static CLASS_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// `EDGES[a][b]` is the call site that first acquired class `b` while holding class `a`.
+static EDGES: [[AtomicPtr<Location<'static>>; MAX_CLASSES]; MAX_CLASSES] = [NO_EDGES; MAX_CLASSES];
+
+static HELD: [HartState; MAX_HARTS] = [HartState::NEW; MAX_HARTS];
+
+struct ClassTable(UnsafeCell<[Key; MAX_CLASSES]>);
+
+unsafe impl Sync for ClassTable {}
+
+/// Set the function that returns the id of the current hart, or `None` if it's not known yet.
+///
+/// Until this is called, no lock is validated.
+pub fn set_hart_id(f: fn() -> Option<usize>) {
+    HART_ID.store(f as usize, Ordering::Release);
+}
+
+/// Return the id of the current hart, if it's known.
+pub(super) fn hart_id() -> Option<usize> {
+    match HART_ID.load(Ordering::Acquire) {
+        0 => None,
+        f => {
+            let f = unsafe { core::mem::transmute::<usize, fn() -> Option<usize>>(f) };
+            f().filter(|&id| id < MAX_HARTS)
+        }
+    }
+}
+
+/// Return the key of a class.
+fn key(class: usize) -> Key {
+    unsafe { (*CLASSES.0.get())[class] }
+}
+
+/// Find the class with the given key, or create a new one.
+fn lookup(key: Key) -> Option<usize> {
+    let find = |count| (0..count).find(|&class| self::key(class).matches(&key));
+
+    let count = CLASS_COUNT.load(Ordering::Acquire);
+    if let Some(class) = find(count) {
+        return Some(class);
+    }
+
+    // an interrupt on this hart must not spin for the class lock
+    let enabled = super::irq::disable_interrupts();
+    while CLASS_LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+
+    // another hart might have created the class in the meantime
+    let count = CLASS_COUNT.load(Ordering::Acquire);
+    let class = find(count).or_else(|| {
+        (count < MAX_CLASSES).then(|| {
+            unsafe { (*CLASSES.0.get())[count] = key };
+            CLASS_COUNT.store(count + 1, Ordering::Release);
+            count
+        })
+    });
+
+    CLASS_LOCK.store(false, Ordering::Release);
+    super::irq::restore_interrupts(enabled);
+    class
+}
+
+/// Run `f` with the held locks of the current hart, if the validator is active.
+fn with_held<R>(f: impl FnOnce(usize, &mut HeldLocks) -> R) -> Option<R> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let hart = hart_id()?;
+    let enabled = super::irq::disable_interrupts();
+    let res = f(hart, unsafe { &mut *HELD[hart].0.get() });
+    super::irq::restore_interrupts(enabled);
+    Some(res)
+}
+
+/// Validate and record the acquisition of a lock, whose data has the type `name`.
+///
+/// If `check` is `false`, the lock is only recorded as held. This is used for `try_lock`,
+/// which can't deadlock. Returns the class, that must be passed to [`release`].
+pub(super) fn acquire(
+    cache: &ClassCache,
+    name: &'static str,
+    site: Site,
+    check: bool,
+) -> Option<usize> {
+    let class = cache.get(name)?;
+
+    with_held(|hart, held| {
+        if check {
+            for &(other, other_site) in &held.locks[..held.depth] {
+                if other == class {
+                    report(Report::Recursive {
+                        hart,
+                        class,
+                        site,
+                        held: other_site,
+                    });
+                }
+
+                if let Some(chain) = path(class, other) {
+                    report(Report::Inversion {
+                        hart,
+                        class,
+                        site,
+                        held: other,
+                        held_site: other_site,
+                        chain,
+                    });
+                }
+            }
+
+            for &(other, _) in &held.locks[..held.depth] {
+                let _ = EDGES[other][class].compare_exchange(
+                    ptr::null_mut(),
+                    site as *const _ as *mut _,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+            }
+        }
+
+        // too deeply nested locks are not tracked
+        if held.depth < MAX_HELD {
+            held.locks[held.depth] = (class, Some(site));
+            held.depth += 1;
+        }
+    })?;
+
+    Some(class)
+}
+
+/// Record the release of a lock of the given class.
+pub(super) fn release(class: Option<usize>) {
+    let class = match class {
+        Some(class) => class,
+        None => return,
+    };
+
+    with_held(|_, held| {
+        // locks don't have to be released in order
+        let locks = &mut held.locks[..held.depth];
+        if let Some(idx) = locks.iter().rposition(|&(other, _)| other == class) {
+            locks[idx..].rotate_left(1);
+            held.depth -= 1;
+        }
+    });
+}
+
+/// Move the held locks of the current hart into `prev`, and replace them with the locks
+/// of `next`, when the scheduler switches from one thread to another.
+///
+/// The `keep` most recently acquired locks stay held by the hart, because they belong to
+/// the scheduler itself, which releases them after the switch.
+pub fn switch(prev: &mut HeldLocks, next: &HeldLocks, keep: usize) {
+    let done = with_held(|_, held| {
+        let split = held.depth.saturating_sub(keep);
+        prev.locks[..split].copy_from_slice(&held.locks[..split]);
+        prev.depth = split;
+
+        // the kept locks are put on top of the locks of the next thread
+        let kept = cmp::min(held.depth - split, MAX_HELD - next.depth);
+        let mut locks = next.locks;
+        locks[next.depth..next.depth + kept].copy_from_slice(&held.locks[split..split + kept]);
+        held.locks = locks;
+        held.depth = next.depth + kept;
+    });
+
+    if done.is_none() {
+        *prev = HeldLocks::new();
+    }
+}
+
+/// A chain of classes that are connected by edges, starting at the class that is acquired.
+///
+/// Class indices are stored as bytes, since the search might run on a small trap stack.
+struct Chain {
+    len: usize,
+    classes: [u8; MAX_CLASSES],
+}
+
+/// Find a path of edges from `from` to `to`.
+fn path(from: usize, to: usize) -> Option<Chain> {
+    const NONE: u8 = u8::MAX;
+
+    // breadth first search, that remembers the predecessor of every class
+    let mut prev = [NONE; MAX_CLASSES];
+    let mut queue = [0u8; MAX_CLASSES];
+    let (mut head, mut tail) = (0, 1);
+    queue[0] = from as u8;
+    prev[from] = from as u8;
+
+    while head < tail {
+        let class = queue[head] as usize;
+        head += 1;
+
+        for (next, edge) in EDGES[class].iter().enumerate() {
+            if prev[next] != NONE || edge.load(Ordering::Relaxed).is_null() {
+                continue;
+            }
+
+            prev[next] = class as u8;
+            if next == to {
+                // walk the predecessors back to the start
+                let mut chain = Chain {
+                    len: 0,
+                    classes: [0; MAX_CLASSES],
+                };
+                let mut class = to;
+                while class != from {
+                    chain.classes[chain.len] = class as u8;
+                    chain.len += 1;
+                    class = prev[class] as usize;
+                }
+                chain.classes[chain.len] = from as u8;
+                chain.len += 1;
+                chain.classes[..chain.len].reverse();
+                return Some(chain);
+            }
+
+            queue[tail] = next as u8;
+            tail += 1;
+        }
+    }
+
+    None
+}
+
+enum Report {
+    Recursive {
+        hart: usize,
+        class: usize,
+        site: Site,
+        held: Option<Site>,
+    },
+    Inversion {
+        hart: usize,
+        class: usize,
+        site: Site,
+        held: usize,
+        held_site: Option<Site>,
+        chain: Chain,
+    },
+}
+
+/// Disable the validator and panic with the given report.
+#[cold]
+fn report(report: Report) -> ! {
+    ENABLED.store(false, Ordering::Relaxed);
+    panic!("{}", report)
+}
+
+/// Display helper for the optional call site of a held lock.
+struct DisplaySite(Option<Site>);
+
+impl fmt::Display for DisplaySite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(site) => fmt::Display::fmt(site, f),
+            None => f.write_str("<unknown>"),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Report::Recursive {
+                hart,
+                class,
+                site,
+                held,
+            } => write!(
+                f,
+                "recursive locking on hart {}: `{}` acquired at {}, \
+                 but it's already held since {}",
+                hart,
+                key(class),
+                site,
+                DisplaySite(held),
+            ),
+            Report::Inversion {
+                hart,
+                class,
+                site,
+                held,
+                held_site,
+                ref chain,
+            } => {
+                writeln!(
+                    f,
+                    "lock order inversion on hart {}: `{}` acquired at {}, \
+                     while holding `{}` acquired at {}",
+                    hart,
+                    key(class),
+                    site,
+                    key(held),
+                    DisplaySite(held_site),
+                )?;
+                write!(f, "which was taken in the opposite order before:")?;
+
+                for pair in chain.classes[..chain.len].windows(2) {
+                    let (from, to) = (pair[0] as usize, pair[1] as usize);
+                    let site = EDGES[from][to].load(Ordering::Relaxed);
+                    write!(
+                        f,
+                        "\n  `{}` acquired at {}, while holding `{}`",
+                        key(to),
+                        DisplaySite(unsafe { site.as_ref() }),
+                        key(from),
+                    )?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
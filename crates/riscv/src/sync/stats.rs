@@ -0,0 +1,104 @@
+//! Lock contention statistics.
+//!
+//! Every lock counts how often it was acquired, how often and how long a hart had to spin for
+//! it, and the longest time it was held. Locks that should show up in [`for_each`] must be
+//! [`register`]ed once, which is only possible for locks that live forever.
+
+use super::Mutex;
+use crate::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// The maximum number of locks that can be registered.
+pub const MAX_LOCKS: usize = 32;
+
+/// All registered locks.
+static LOCKS: Mutex<[Option<(&'static str, &'static LockStats)>; MAX_LOCKS]> =
+    Mutex::new([None; MAX_LOCKS]);
+
+/// The counters of a single lock.
+pub struct LockStats {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    spins: AtomicU64,
+    max_hold: AtomicU64,
+}
+
+impl LockStats {
+    pub(super) const fn new() -> Self {
+        Self {
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            spins: AtomicU64::new(0),
+            max_hold: AtomicU64::new(0),
+        }
+    }
+
+    /// Count an acquisition of the lock.
+    #[inline]
+    pub(super) fn acquired(&self) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the spins it took to acquire the lock, if there were any.
+    #[inline]
+    pub(super) fn contended(&self, spins: usize) {
+        if spins != 0 {
+            self.contended.fetch_add(1, Ordering::Relaxed);
+            self.spins.fetch_add(spins as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Record the hold time of a lock that was acquired at the given time.
+    #[inline]
+    pub(super) fn released(&self, since: usize) {
+        let held = asm::rdtime().wrapping_sub(since) as u64;
+        self.max_hold.fetch_max(held, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the current counters.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            spins: self.spins.load(Ordering::Relaxed),
+            max_hold: asm::ticks_to_duration(self.max_hold.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// The counters of a lock at a single point in time.
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    /// The number of times the lock was acquired.
+    pub acquisitions: u64,
+    /// The number of acquisitions that had to wait for the lock.
+    pub contended: u64,
+    /// The total number of spins of all contended acquisitions.
+    pub spins: u64,
+    /// The longest time the lock was held.
+    pub max_hold: Duration,
+}
+
+/// Register the statistics of a lock under the given name.
+///
+/// Returns `false` if there are already [`MAX_LOCKS`] locks registered.
+pub fn register(name: &'static str, stats: &'static LockStats) -> bool {
+    let mut locks = LOCKS.lock();
+    match locks.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some((name, stats));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Call the closure with the name and a snapshot of every registered lock.
+pub fn for_each(mut f: impl FnMut(&'static str, Snapshot)) {
+    // don't hold the registry lock while calling the closure, it might log something
+    let locks = *LOCKS.lock();
+    for (name, stats) in locks.iter().flatten() {
+        f(name, stats.snapshot());
+    }
+}
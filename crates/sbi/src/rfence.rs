@@ -2,10 +2,10 @@
 
 use super::{Error, HartMask, SbiResult};
 
-/// The unique id of the IPI extension.
+/// The unique id of the RFENCE extension.
 pub const EXTENSION_ID: u32 = 0x52464E43;
 
-fn sbi_call(fid: u32, mask: HartMask, start: usize, size: usize) -> SbiResult<()> {
+fn sbi_call(fid: u32, mask: HartMask, start: usize, size: usize, asid: usize) -> SbiResult<()> {
     let err_code: usize;
     unsafe {
         asm!("ecall",
@@ -16,6 +16,7 @@ fn sbi_call(fid: u32, mask: HartMask, start: usize, size: usize) -> SbiResult<()
             inout("a1") mask.base => _,
             inout("a2") start => _,
             inout("a3") size => _,
+            inout("a4") asid => _,
         );
     }
     Error::from_sbi_call((), err_code as isize)
@@ -23,11 +24,21 @@ fn sbi_call(fid: u32, mask: HartMask, start: usize, size: usize) -> SbiResult<()
 
 /// Instructs the harts specified by `mask` to execute a FENCE.I instruction.
 pub fn fence_i(mask: HartMask) -> SbiResult<()> {
-    sbi_call(0x00, mask, 0, 0)
+    sbi_call(0x00, mask, 0, 0, 0)
 }
 
 /// Instructs the harts specified by `mask` to execute SFENCE.VMA instructions, covering the range
 /// from `start` with `size` bytes.
+///
+/// A `size` of `usize::MAX` flushes the whole TLB.
 pub fn sfence_vma(mask: HartMask, start: usize, size: usize) -> SbiResult<()> {
-    sbi_call(0x00, mask, start, size)
+    sbi_call(0x01, mask, start, size, 0)
+}
+
+/// Instructs the harts specified by `mask` to execute SFENCE.VMA instructions for the given
+/// ASID, covering the range from `start` with `size` bytes.
+///
+/// A `size` of `usize::MAX` flushes every entry of the ASID.
+pub fn sfence_vma_asid(mask: HartMask, start: usize, size: usize, asid: u16) -> SbiResult<()> {
+    sbi_call(0x02, mask, start, size, asid as usize)
 }
@@ -4,6 +4,11 @@ use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
 use xshell::cmd;
 
+/// The address the kernel is linked at.
+///
+/// Keep in sync with `LINK_START` inside the kernel.
+const LINK_START: u64 = 0x8020_0000;
+
 const HELP: &str = "\
 xtask
     The build system and 'Makefile' for NovOS
@@ -140,6 +145,9 @@ pub fn run(no_release: bool, spike: bool, mut args: Arguments) -> Result<()> {
 }
 
 /// Build the kernel
+///
+/// The kernel is linked twice. The symbol table is generated from the first binary,
+/// and then added to the kernel in the second link.
 fn build(no_release: bool, spike: bool) -> Result<()> {
     let release = if no_release { &[][..] } else { &["--release"] };
 
@@ -151,12 +159,98 @@ fn build(no_release: bool, spike: bool) -> Result<()> {
         "target/riscv64gc-unknown-none-elf/release/kernel"
     };
 
+    // the linker script puts the symbol table behind all code, so no function is moved
+    let symbols = format!("{}.symbols", path);
+    std::fs::write(&symbols, symbol_table(path)?)?;
+    cmd!(
+        "cargo rustc -p kernel {release...} --
+            -Clink-arg=--format=binary
+            -Clink-arg={symbols}
+            -Clink-arg=--format=default
+        "
+    )
+    .run()?;
+
     if spike {
         cmd!("llvm-objcopy --set-start=0x80200000 -O binary {path} {path}.bin").run()?;
     }
     Ok(())
 }
 
+/// Generate the symbol table for the kernel binary at `path`.
+///
+/// The table starts with the magic `KSYM` and the number of symbols. It's followed by an entry
+/// for every function, sorted by address, and the string table. Each entry consists of the
+/// address relative to `LINK_START`, the size, and the offset and length of the name inside
+/// the string table. All numbers are little endian `u32`s.
+fn symbol_table(path: &str) -> Result<Vec<u8>> {
+    let output =
+        cmd!("llvm-nm --defined-only --print-size --numeric-sort --demangle {path}").read()?;
+
+    let mut symbols = Vec::new();
+    for line in output.lines() {
+        // every line has the format `<addr> [<size>] <type> <name>`,
+        // where the size is missing for symbols without a size
+        let mut parts = line.splitn(2, ' ');
+        let (addr, rest) = match (parts.next(), parts.next()) {
+            (Some(addr), Some(rest)) => (addr, rest),
+            _ => continue,
+        };
+
+        let (size, rest) = match rest.split_once(' ') {
+            Some((kind, _)) if kind.len() == 1 => ("0", rest),
+            Some((size, rest)) => (size, rest),
+            None => continue,
+        };
+
+        let (kind, name) = match rest.split_once(' ') {
+            Some(x) => x,
+            None => continue,
+        };
+
+        // only functions are interesting
+        if !matches!(kind, "t" | "T" | "w" | "W") {
+            continue;
+        }
+
+        let addr = u64::from_str_radix(addr, 16)?;
+        let size = u64::from_str_radix(size, 16)?;
+        if addr < LINK_START {
+            continue;
+        }
+
+        symbols.push((addr - LINK_START, size, strip_hash(name)));
+    }
+
+    // aliases of the same function are only stored once
+    symbols.dedup_by_key(|(addr, ..)| *addr);
+
+    let mut table = b"KSYM".to_vec();
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+
+    let mut strings = Vec::new();
+    for (addr, size, name) in &symbols {
+        table.extend_from_slice(&(*addr as u32).to_le_bytes());
+        table.extend_from_slice(&(*size as u32).to_le_bytes());
+        table.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        strings.extend_from_slice(name.as_bytes());
+    }
+
+    table.extend_from_slice(&strings);
+    Ok(table)
+}
+
+/// Remove the hash from a demangled Rust symbol, like `kernel::main::h0123456789abcdef`.
+fn strip_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            path
+        }
+        _ => name,
+    }
+}
+
 fn watch() -> Result<()> {
     cmd!("cargo watch -c -x 'clippy -p kernel' -x 'doc -p kernel'").run()?;
     Ok(())